@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use lib::{assembler::assembler::Assembler, vm::VM};
+
+/// Counts down from 20000, summing and dividing along the way, which is
+/// about 140000 instructions
+const PROGRAM: &str = "\
+LOAD $1 #20000
+LOAD $4 #3
+loop: DEC $1
+ADD $2 $2 $1
+DIV $3 $2 $4
+GT $5 $1 $0
+LOAD $6 @loop
+JEQ $6 $5
+HLT";
+
+fn vm() -> VM {
+    let mut vm = VM::new();
+    vm.program = Assembler::new().assemble(PROGRAM).unwrap();
+
+    vm
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+
+    group.bench_function("bytes", |b| {
+        b.iter_batched_ref(vm, |vm| vm.run().unwrap(), criterion::BatchSize::SmallInput)
+    });
+    group.bench_function("predecoded", |b| {
+        b.iter_batched_ref(
+            vm,
+            |vm| vm.run_fast().unwrap(),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
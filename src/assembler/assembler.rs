@@ -1,10 +1,22 @@
-use core::panic;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{assembler::parser::Parser, instruction};
+use crate::{
+    assembler::parser::Parser,
+    debug_info::{DebugInfo, DebugLabel, SectionRange, SourceLocation, SourceRange},
+    instruction::INSTRUCTION_SIZE,
+};
 
 use super::{
-    assembler_instruction::{AssemblerInstruction, AssemblerToken},
+    assembler_instruction::{evaluate_operand, AssemblerInstruction, AssemblerToken},
+    error::{AssemblerError, ErrorKind},
+    listing::{Listing, ListingLine},
     symbol::{Symbol, SymbolTable, SymbolType},
+    Location, Token,
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -13,13 +25,53 @@ pub enum AssemblerPhase {
     PhaseTwo,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Section {
+    Code,
+    Data,
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Section::Code => "code",
+            Section::Data => "data",
+        })
+    }
+}
+
+/// Where the first phase put a statement
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Placement {
+    section: Section,
+    address: u32,
+    /// Number of bytes the statement adds to its section
+    size: u32,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
     pub sections: Vec<String>,
+    pub current_section: Section,
     pub read_only_data: Vec<u8>,
     pub const_offset: u32,
+    pub code_offset: u32,
+    /// Directories searched by `.include` after the directory of the including file
+    pub include_paths: Vec<PathBuf>,
+    /// Constants defined before assembly starts, like defines given on the command line
+    pub defines: HashMap<String, i64>,
+    /// Whether assembling fills in `listing`
+    pub generate_listing: bool,
+    pub listing: Option<Listing>,
+    /// Whether assembling fills in `debug_info`
+    pub generate_debug_info: bool,
+    pub debug_info: Option<DebugInfo>,
+    /// Whether labels that are never defined are assembled as 0 instead of
+    /// failing, their names are put in `undefined`
+    pub allow_undefined: bool,
+    pub undefined: Vec<String>,
 }
 
 impl Assembler {
@@ -28,67 +80,328 @@ impl Assembler {
             phase: AssemblerPhase::PhaseOne,
             symbols: SymbolTable::new(),
             sections: vec![],
+            current_section: Section::Code,
             read_only_data: vec![],
             const_offset: 0,
+            code_offset: 0,
+            include_paths: vec![],
+            defines: HashMap::new(),
+            generate_listing: false,
+            listing: None,
+            generate_debug_info: false,
+            debug_info: None,
+            allow_undefined: false,
+            undefined: vec![],
         }
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        let mut p = Parser::new(raw);
-        p.parse();
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, AssemblerError> {
+        self.assemble_with(Parser::new(raw))
+    }
+
+    /// Assembles the file at `path`, errors name the file they happened in
+    pub fn assemble_file(&mut self, path: impl AsRef<Path>) -> Result<Vec<u8>, AssemblerError> {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path).map_err(|e| {
+            AssemblerError::new(
+                ErrorKind::Io {
+                    path: path.display().to_string(),
+                    message: e.to_string(),
+                },
+                Location::default(),
+            )
+        })?;
+
+        self.assemble_with(Parser::with_file(&raw, path))
+    }
+
+    /// Assembles the named sources one after the other, errors name the
+    /// source they happened in
+    pub fn assemble_sources(
+        &mut self,
+        sources: &[(Arc<Path>, String)],
+    ) -> Result<Vec<u8>, AssemblerError> {
+        self.assemble_with(Parser::with_sources(sources))
+    }
+
+    fn assemble_with(&mut self, mut p: Parser) -> Result<Vec<u8>, AssemblerError> {
+        p.include_paths = self.include_paths.clone();
+        for (name, value) in &self.defines {
+            p.define(name, *value);
+            self.symbols
+                .add_symbol(Symbol::new(name.clone(), *value, SymbolType::Constant));
+        }
+        p.parse()?;
+
+        let placements = self.first_phase(&p.program, &p.locations)?;
+        let encoded = self.second_phase(&p.program, &p.locations, &placements)?;
+
+        if self.generate_listing {
+            self.listing = Some(self.create_listing(&p, &placements, &encoded));
+        }
+        if self.generate_debug_info {
+            self.debug_info = Some(create_debug_info(&p, &placements));
+        }
 
-        self.first_phase(&p.program);
-        Some(self.second_phase(&p.program))
+        Ok(encoded.concat())
     }
 
-    fn first_phase(&mut self, program: &Vec<AssemblerToken>) {
-        self.create_symbol_table(program);
+    /// Lays out the program and returns where each statement ended up
+    fn first_phase(
+        &mut self,
+        program: &[AssemblerToken],
+        locations: &[Location],
+    ) -> Result<Vec<Placement>, AssemblerError> {
+        let placements = self.create_symbol_table(program, locations)?;
         self.phase = AssemblerPhase::PhaseTwo;
+
+        Ok(placements)
     }
 
-    fn create_symbol_table(&mut self, program: &Vec<AssemblerToken>) {
-        for i in program {
-            match i {
+    fn create_symbol_table(
+        &mut self,
+        program: &[AssemblerToken],
+        locations: &[Location],
+    ) -> Result<Vec<Placement>, AssemblerError> {
+        let mut placements = vec![];
+
+        for (i, location) in program.iter().zip(locations) {
+            let (label, instruction) = match i {
                 AssemblerToken::LabelDeclaration {
                     label_name: name,
                     assembler_instruction: instruction,
-                } => {
-                    let symbol = Symbol::new(
-                        String::from(name),
-                        instruction.to_bytes(),
-                        SymbolType::Label,
-                    );
+                } => (Some(name), instruction),
+                AssemblerToken::Instruction {
+                    assembler_instruction: instruction,
+                } => (None, instruction),
+            };
 
-                    self.symbols.add_symbol(symbol);
+            // Raw bytes can only leave the code unaligned at the very end,
+            // nothing after them could be decoded
+            if self.current_section == Section::Code
+                && (label.is_some() || instruction.opcode.is_some())
+                && !(self.code_offset as usize).is_multiple_of(INSTRUCTION_SIZE)
+            {
+                return Err(AssemblerError::new(
+                    ErrorKind::UnalignedCode {
+                        address: self.code_offset,
+                    },
+                    location.clone(),
+                ));
+            }
 
-                    if instruction.is_directive() {
-                        self.process_directive(instruction)
-                    }
+            if let Some(name) = label {
+                let offset = match self.current_section {
+                    Section::Code => self.code_offset,
+                    Section::Data => self.const_offset,
+                };
+                self.define_symbol(name, offset as i64, SymbolType::Label)
+                    .map_err(|kind| AssemblerError::new(kind, location.clone()))?;
+            }
+
+            let code_size = match &instruction.opcode {
+                Some(Token::PseudoOp { code }) => {
+                    4 * code.length(instruction, &self.symbols) as u32
+                }
+                Some(_) => 4,
+                None if self.current_section == Section::Code => instruction.data_size(),
+                None => 0,
+            };
+            let const_offset = self.const_offset;
+
+            if instruction.is_directive() {
+                self.process_directive(instruction)
+                    .map_err(|kind| AssemblerError::new(kind, location.clone()))?;
+            }
+
+            let placement = if code_size > 0 {
+                Placement {
+                    section: Section::Code,
+                    address: self.code_offset,
+                    size: code_size,
+                }
+            } else if self.const_offset > const_offset {
+                Placement {
+                    section: Section::Data,
+                    address: const_offset,
+                    size: self.const_offset - const_offset,
                 }
+            } else {
+                Placement {
+                    section: self.current_section,
+                    address: match self.current_section {
+                        Section::Code => self.code_offset,
+                        Section::Data => self.const_offset,
+                    },
+                    size: 0,
+                }
+            };
+
+            self.code_offset += code_size;
+            placements.push(placement);
+        }
+
+        Ok(placements)
+    }
+
+    fn second_phase(
+        &mut self,
+        program: &[AssemblerToken],
+        locations: &[Location],
+        placements: &[Placement],
+    ) -> Result<Vec<Vec<u8>>, AssemblerError> {
+        let mut encoded = vec![];
+
+        for ((i, location), placement) in program.iter().zip(locations).zip(placements) {
+            let instruction = match i {
+                AssemblerToken::LabelDeclaration {
+                    assembler_instruction: instruction,
+                    ..
+                } => instruction,
                 AssemblerToken::Instruction {
                     assembler_instruction: instruction,
-                } => {
-                    if instruction.is_directive() && !instruction.has_operands() {
-                        if let Some(directive) = &instruction.directive {
-                            self.sections.push(directive.to_string())
+                } => instruction,
+            };
+
+            let bytes = match placement.section {
+                Section::Code => loop {
+                    match self.encode(instruction, placement.size) {
+                        Ok(bytes) => break bytes,
+                        // Placeholders only exist in this phase, so the layout
+                        // is the same as if the labels had been defined
+                        Err(ErrorKind::UndefinedSymbol { name })
+                            if self.allow_undefined && self.symbols.get_symbol(&name).is_none() =>
+                        {
+                            self.symbols.add_symbol(Symbol::new(
+                                name.clone(),
+                                0,
+                                SymbolType::Label,
+                            ));
+                            self.undefined.push(name);
                         }
+                        Err(kind) => return Err(AssemblerError::new(kind, location.clone())),
+                    }
+                },
+                Section::Data => vec![],
+            };
+            encoded.push(bytes);
+        }
+
+        for name in &self.undefined {
+            self.symbols.symbols.remove(name);
+        }
+
+        Ok(encoded)
+    }
+
+    fn create_listing(&self, p: &Parser, placements: &[Placement], encoded: &[Vec<u8>]) -> Listing {
+        let lines = p
+            .locations
+            .iter()
+            .zip(placements)
+            .zip(encoded)
+            .map(|((location, placement), code)| {
+                let bytes = match placement.section {
+                    Section::Code => code.clone(),
+                    Section::Data => {
+                        let start = placement.address as usize;
+                        self.read_only_data[start..start + placement.size as usize].to_vec()
                     }
+                };
+                let source = p
+                    .files
+                    .get(&location.file)
+                    .and_then(|text| text.lines().nth(location.line.saturating_sub(1)))
+                    .unwrap_or_default();
+
+                ListingLine {
+                    section: placement.section,
+                    address: placement.address,
+                    bytes,
+                    location: location.clone(),
+                    source: String::from(source.trim()),
+                }
+            })
+            .collect();
+
+        let sections = p
+            .program
+            .iter()
+            .zip(placements)
+            .filter_map(|(token, placement)| match token {
+                AssemblerToken::LabelDeclaration { label_name, .. } => {
+                    Some((label_name.clone(), placement.section))
+                }
+                AssemblerToken::Instruction { .. } => None,
+            })
+            .collect();
+
+        Listing::new(lines, &self.symbols, &sections)
+    }
+
+    /// Encodes a statement that was laid out as `size` bytes of code
+    fn encode(&self, instruction: &AssemblerInstruction, size: u32) -> Result<Vec<u8>, ErrorKind> {
+        match &instruction.opcode {
+            Some(Token::PseudoOp { code }) => {
+                let mut bytes = vec![];
+                for real in code.expand(instruction, &self.symbols, size as usize / 4)? {
+                    bytes.append(&mut real.to_bytes(&self.symbols)?);
                 }
+
+                Ok(bytes)
             }
+            Some(_) => instruction.to_bytes(&self.symbols),
+            None if size > 0 => instruction.data_bytes(&self.symbols),
+            None => Ok(vec![]),
         }
     }
 
-    fn second_phase(&self, program: &[AssemblerToken]) -> Vec<u8> {
-        vec![00, 21, 21]
+    fn define_symbol(
+        &mut self,
+        name: &str,
+        value: i64,
+        symbol_type: SymbolType,
+    ) -> Result<(), ErrorKind> {
+        let symbol = Symbol::new(String::from(name), value, symbol_type);
+
+        if self.symbols.add_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(ErrorKind::DuplicateSymbol {
+                name: String::from(name),
+            })
+        }
     }
 
-    fn process_directive(&mut self, instruction: &AssemblerInstruction) {
+    fn process_directive(&mut self, instruction: &AssemblerInstruction) -> Result<(), ErrorKind> {
         if let Some(name) = instruction.get_directive_name() {
             match name {
-                ".asciiz" => self.handle_ascii(instruction),
-                _ => panic!("Unknown directive"),
+                "data" | "code" if !instruction.has_operands() => {
+                    self.current_section = match name {
+                        "data" => Section::Data,
+                        _ => Section::Code,
+                    };
+                    self.sections.push(format!(".{name}"));
+                }
+                "asciiz" => self.handle_ascii(instruction),
+                // In the code section these are encoded with the instructions
+                "byte" | "word" => {
+                    if self.current_section == Section::Data {
+                        let mut bytes = instruction.data_bytes(&self.symbols)?;
+                        self.const_offset += bytes.len() as u32;
+                        self.read_only_data.append(&mut bytes);
+                    }
+                }
+                "equ" => self.handle_equ(instruction)?,
+                _ => {
+                    return Err(ErrorKind::UnknownDirective {
+                        name: String::from(name),
+                    })
+                }
             }
         }
+
+        Ok(())
     }
 
     fn handle_ascii(&mut self, i: &AssemblerInstruction) {
@@ -102,31 +415,105 @@ impl Assembler {
             self.const_offset += 1
         }
     }
+
+    /// Defines a constant, the value can only refer to symbols defined before it
+    fn handle_equ(&mut self, i: &AssemblerInstruction) -> Result<(), ErrorKind> {
+        let name = match &i.operand_one {
+            Some(Token::Identifier { name }) => name,
+            Some(found) => {
+                return Err(ErrorKind::InvalidOperand {
+                    found: found.clone(),
+                })
+            }
+            None => return Err(ErrorKind::UnexpectedToken { found: Token::EOF }),
+        };
+
+        let value = match &i.operand_two {
+            Some(t) => evaluate_operand(t, &self.symbols)?,
+            None => return Err(ErrorKind::UnexpectedToken { found: Token::EOF }),
+        };
+
+        self.define_symbol(name, value, SymbolType::Constant)
+    }
+}
+
+fn create_debug_info(p: &Parser, placements: &[Placement]) -> DebugInfo {
+    let mut info = DebugInfo::default();
+
+    for ((token, location), placement) in p.program.iter().zip(&p.locations).zip(placements) {
+        if let AssemblerToken::LabelDeclaration { label_name, .. } = token {
+            info.labels.push(DebugLabel {
+                name: label_name.clone(),
+                section: placement.section,
+                address: placement.address,
+            });
+        }
+
+        let end = placement.address + placement.size;
+        if placement.section == Section::Code && placement.size > 0 {
+            info.ranges.push(SourceRange {
+                start: placement.address,
+                end,
+                location: SourceLocation {
+                    file: location
+                        .file
+                        .as_ref()
+                        .map(|file| file.display().to_string()),
+                    line: location.line,
+                    column: location.column,
+                },
+            });
+        }
+
+        // Statements that take no space don't open a section of their own
+        match info.sections.last_mut() {
+            Some(last) if last.section == placement.section => last.end = end,
+            _ if placement.size > 0 => info.sections.push(SectionRange {
+                section: placement.section,
+                start: placement.address,
+                end,
+            }),
+            _ => {}
+        }
+    }
+
+    info
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::assembler::assembler::Assembler;
+    use crate::{
+        assembler::{
+            assembler::Assembler,
+            error::{AssemblerError, ErrorKind},
+            macros::MAX_REPEAT_TOKENS,
+            Location,
+        },
+        vm::VM,
+    };
 
     #[test]
     fn test_symbol_assembler() {
         let mut assembler = Assembler::new();
 
-        assembler.assemble("LOAD $0 #10\nLOAD $1 #10\nmy_label: ADD $2 $0 $1");
+        assembler
+            .assemble("LOAD $0 #10\nLOAD $1 #10\nmy_label: ADD $2 $0 $1")
+            .unwrap();
 
-        let instruction: Vec<u8> = vec![01, 02, 00, 01];
-
-        assert_eq!(
-            assembler.symbols.get_symbol(String::from("my_label")),
-            Some(&instruction)
-        );
+        assert_eq!(assembler.symbols.value("my_label"), Some(8));
     }
 
     #[test]
     fn test_sections() {
         let mut assembler = Assembler::new();
 
-        assembler.assemble(".data\n.code\nLOAD $1 #10");
+        assembler.assemble(".data\n.code\nLOAD $1 #10").unwrap();
 
         assert_eq!(
             assembler.sections,
@@ -138,8 +525,571 @@ mod tests {
     fn test_read_only_data() {
         let mut assembler = Assembler::new();
 
-        assembler.assemble(".data\nmy_string: .asciiz \"Hello world\"");
+        assembler
+            .assemble(".data\nmy_string: .asciiz \"Hello world\"")
+            .unwrap();
 
         assert_eq!(assembler.read_only_data.len(), "Hello world".len() + 1);
     }
+
+    #[test]
+    fn test_constant_expressions() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler
+            .assemble(
+                ".equ ENTRY_SIZE #4\n\
+                 .equ ENTRIES ENTRY_SIZE * (#2 + #1)\n\
+                 start: LOAD $0 ENTRIES << #1 | #1\n\
+                 LOAD $1 ~#0 & #255\n\
+                 LOAD $2 @end - @start\n\
+                 end:",
+            )
+            .unwrap();
+
+        assert_eq!(program, vec![0, 0, 0, 25, 0, 1, 0, 255, 0, 2, 0, 12]);
+        assert_eq!(assembler.symbols.value("ENTRIES"), Some(12));
+    }
+
+    #[test]
+    fn test_constant_expression_errors() {
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("LOAD $0 #1\nLOAD $0 #65535 + #1"),
+            Err(AssemblerError::new(
+                ErrorKind::OperandOutOfRange {
+                    value: 65536,
+                    bits: 16
+                },
+                Location::new(2, 1)
+            ))
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("LOAD $0 #0 - #1"),
+            Err(AssemblerError::new(
+                ErrorKind::OperandOutOfRange {
+                    value: -1,
+                    bits: 16
+                },
+                Location::new(1, 1)
+            ))
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("LOAD $0 #-1"),
+            Err(AssemblerError::new(
+                ErrorKind::OperandOutOfRange {
+                    value: -1,
+                    bits: 16
+                },
+                Location::new(1, 1)
+            ))
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble(".equ SIZE LATER\n.equ LATER #1"),
+            Err(AssemblerError::new(
+                ErrorKind::UndefinedSymbol {
+                    name: String::from("LATER")
+                },
+                Location::new(1, 1)
+            ))
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("LOAD $0 #10 / (#2 - #2)"),
+            Err(AssemblerError::new(
+                ErrorKind::DivisionByZero,
+                Location::new(1, 1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_macro_expansion() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler
+            .assemble(
+                ".macro countdown reg, times\n\
+                 LOAD reg times\n\
+                 again: DEC reg\n\
+                 LOAD $31 @again\n\
+                 .endm\n\
+                 countdown $1, #2 + #1\n\
+                 countdown $2, #5",
+            )
+            .unwrap();
+
+        assert_eq!(
+            program,
+            vec![0, 1, 0, 3, 19, 1, 0, 0, 0, 31, 0, 4, 0, 2, 0, 5, 19, 2, 0, 0, 0, 31, 0, 16]
+        );
+        assert_eq!(assembler.symbols.value("again#0"), Some(4));
+        assert_eq!(assembler.symbols.value("again#1"), Some(16));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let mut assembler = Assembler::new();
+        let error = assembler
+            .assemble(".macro broken\nLOAD $0 MISSING\n.endm\n\nbroken")
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "2:1: undefined symbol `MISSING`\n    in expansion of macro `broken` invoked at 5:1"
+        );
+
+        let mut assembler = Assembler::new();
+        let error = assembler
+            .assemble(".macro forever\nforever\n.endm\nforever")
+            .unwrap_err();
+
+        assert_eq!(
+            error.kind,
+            ErrorKind::MacroRecursionLimit {
+                name: String::from("forever"),
+                limit: 64
+            }
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble(".macro open\nHLT"),
+            Err(AssemblerError::new(
+                ErrorKind::UnterminatedMacro {
+                    name: String::from("open")
+                },
+                Location::new(1, 1)
+            ))
+        );
+    }
+
+    /// Creates an empty directory in the system temp dir for tests that need files
+    fn test_directory(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("serus_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        directory
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = test_directory("include");
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::create_dir_all(directory.join("shared")).unwrap();
+        std::fs::write(
+            directory.join("main.sasm"),
+            ".include \"lib/math.sasm\"\nLOAD $0 #1\ntwice $0\nHLT",
+        )
+        .unwrap();
+        // Found next to the including file
+        std::fs::write(
+            directory.join("lib/math.sasm"),
+            ".include \"consts.sasm\"\n.macro twice reg\nADD reg reg reg\n.endm",
+        )
+        .unwrap();
+        // Found through the include paths
+        std::fs::write(directory.join("shared/consts.sasm"), ".equ ONE #1").unwrap();
+
+        let mut assembler = Assembler::new();
+        assembler.include_paths.push(directory.join("shared"));
+
+        let program = assembler
+            .assemble_file(directory.join("main.sasm"))
+            .unwrap();
+
+        assert_eq!(program, vec![0, 0, 0, 1, 1, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(assembler.symbols.value("ONE"), Some(1));
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let directory = test_directory("include_errors");
+        std::fs::write(directory.join("a.sasm"), "HLT\n.include \"b.sasm\"").unwrap();
+        std::fs::write(directory.join("b.sasm"), ".include \"a.sasm\"").unwrap();
+        std::fs::write(directory.join("c.sasm"), "LOAD $0 #1\n\n  LOAD $0 MISSING").unwrap();
+        std::fs::write(directory.join("d.sasm"), ".include \"c.sasm\"").unwrap();
+
+        let error = Assembler::new()
+            .assemble_file(directory.join("a.sasm"))
+            .unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::IncludeCycle { .. }));
+        assert_eq!(
+            error.location.file.as_deref(),
+            Some(directory.join("b.sasm").as_path())
+        );
+
+        let error = Assembler::new()
+            .assemble_file(directory.join("d.sasm"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:3:3: undefined symbol `MISSING`",
+                directory.join("c.sasm").display()
+            )
+        );
+
+        let error = Assembler::new()
+            .assemble(".include \"does_not_exist.sasm\"")
+            .unwrap_err();
+        assert_eq!(
+            error,
+            AssemblerError::new(
+                ErrorKind::IncludeNotFound {
+                    path: String::from("does_not_exist.sasm")
+                },
+                Location::new(1, 1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_conditional_assembly() {
+        let source = ".ifndef DEBUG\n\
+                      .equ DEBUG #0\n\
+                      .endif\n\
+                      .if DEBUG\n\
+                      LOAD $0 #1\n\
+                      .elif LEVEL - #2\n\
+                      LOAD $0 #2\n\
+                      .else\n\
+                      .if #1\n\
+                      LOAD $0 #3\n\
+                      .endif\n\
+                      .endif\n\
+                      .ifdef start\n\
+                      HLT\n\
+                      .endif";
+
+        let mut assembler = Assembler::new();
+        assembler.defines.insert(String::from("DEBUG"), 1);
+        assert_eq!(assembler.assemble(source).unwrap(), vec![0, 0, 0, 1]);
+
+        let mut assembler = Assembler::new();
+        assembler.defines.insert(String::from("LEVEL"), 1);
+        assert_eq!(assembler.assemble(source).unwrap(), vec![0, 0, 0, 2]);
+
+        let mut assembler = Assembler::new();
+        assembler.defines.insert(String::from("LEVEL"), 2);
+        assert_eq!(
+            assembler.assemble(&format!("start:\n{source}")).unwrap(),
+            vec![0, 0, 0, 3, 5, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler
+            .assemble(
+                ".equ TIMES #2\n\
+                 .rept TIMES + #1\n\
+                 step: INC $0\n\
+                 .rept #2\n\
+                 DEC $1\n\
+                 .endr\n\
+                 .endr\n\
+                 .rept #0\n\
+                 HLT\n\
+                 .endr",
+            )
+            .unwrap();
+
+        assert_eq!(
+            program,
+            [[18, 0, 0, 0], [19, 1, 0, 0], [19, 1, 0, 0]]
+                .concat()
+                .repeat(3)
+        );
+        assert_eq!(assembler.symbols.symbols.len(), 4);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let test_cases = [
+            (
+                ".if #1\nHLT",
+                ErrorKind::UnterminatedBlock {
+                    directive: String::from("if"),
+                },
+            ),
+            (
+                ".rept #2\nHLT",
+                ErrorKind::UnterminatedBlock {
+                    directive: String::from("rept"),
+                },
+            ),
+            (
+                ".endif",
+                ErrorKind::UnexpectedToken {
+                    found: crate::assembler::Token::Directive {
+                        value: String::from("endif"),
+                    },
+                },
+            ),
+            (
+                ".rept #0 - #1\n.endr",
+                ErrorKind::InvalidRepeatCount { count: -1 },
+            ),
+            (
+                ".rept #100000000\n.endr",
+                ErrorKind::RepeatLimit {
+                    limit: MAX_REPEAT_TOKENS,
+                },
+            ),
+            (
+                "start:\n.if @start\n.endif",
+                ErrorKind::UndefinedSymbol {
+                    name: String::from("start"),
+                },
+            ),
+        ];
+
+        for (source, expected) in test_cases {
+            let error = Assembler::new().assemble(source).unwrap_err();
+            assert_eq!(error.kind, expected, "{source}");
+        }
+
+        // Nested blocks count together, the error is at the `.rept` that goes
+        // over the limit
+        let error = Assembler::new()
+            .assemble(".rept #1024\n.rept #1024\nINC $1\n.endr\n.endr")
+            .unwrap_err();
+        assert_eq!(
+            error.kind,
+            ErrorKind::RepeatLimit {
+                limit: MAX_REPEAT_TOKENS
+            }
+        );
+        assert_eq!(error.location.line, 2);
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler
+            .assemble(
+                "LI $0 #70000\n\
+                 LI $2 #3\n\
+                 CLR $1\n\
+                 loop: INC $1\n\
+                 BLT $1 $2 @loop\n\
+                 MOV $3 $1\n\
+                 B @end\n\
+                 LOAD $3 #99\n\
+                 end: LI $4 #0 - #1\n\
+                 NOP\n\
+                 HLT",
+            )
+            .unwrap();
+
+        assert_eq!(assembler.symbols.value("loop"), Some(32));
+        assert_eq!(assembler.symbols.value("end"), Some(68));
+        assert_eq!(program.len(), 68 + 6 * 4 + 4 + 4);
+
+        let mut vm = VM::new();
+        vm.program = program;
+        vm.run().unwrap();
+
+        assert_eq!(&vm.registers[..5], &[70000, 3, 3, 3, -1]);
+    }
+
+    #[test]
+    fn test_pseudo_instruction_names() {
+        let mut assembler = Assembler::new();
+
+        // Only where a mnemonic goes are these pseudo-instructions
+        let program = assembler
+            .assemble(
+                ".equ b #1
+                 .macro add_to a, b
+                 ADD a a b
+                 .endm
+                 LOAD $1 b
+                 LOAD $2 #2
+                 add_to $1, $2
+                 li: mov $3 $1
+                 HLT",
+            )
+            .unwrap();
+        assert_eq!(assembler.symbols.value("b"), Some(1));
+        assert_eq!(assembler.symbols.value("li"), Some(12));
+
+        let mut vm = VM::new();
+        vm.program = program;
+        vm.run().unwrap();
+        assert_eq!(&vm.registers[1..4], &[3, 2, 3]);
+
+        let error = Assembler::new()
+            .assemble(
+                ".macro nop
+.endm",
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.kind,
+            ErrorKind::ReservedName {
+                name: String::from("nop")
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "1:8: `nop` is a reserved pseudo-instruction name"
+        );
+    }
+
+    #[test]
+    fn test_pseudo_instruction_errors() {
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("NOP\nMOV $1 $31"),
+            Err(AssemblerError::new(
+                ErrorKind::ReservedRegister { register: 31 },
+                Location::new(2, 1)
+            ))
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("LI $1 #1 << #32"),
+            Err(AssemblerError::new(
+                ErrorKind::OperandOutOfRange {
+                    value: 1 << 32,
+                    bits: 32
+                },
+                Location::new(1, 1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_local_and_numeric_labels() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler
+            .assemble(
+                ".macro spin reg\n\
+                 1: DEC reg\n\
+                 JNEQ @1b reg\n\
+                 .endm\n\
+                 main2: LOAD $1 #3\n\
+                 .loop: spin $1\n\
+                 B @1f\n\
+                 1: B @.loop\n\
+                 1: B @main2.loop\n\
+                 other: B @.loop\n\
+                 .loop: HLT",
+            )
+            .unwrap();
+
+        assert_eq!(program.len(), 4 * 12);
+        assert_eq!(assembler.symbols.value("main2.loop"), Some(4));
+        assert_eq!(assembler.symbols.value("1#0"), Some(4));
+        assert_eq!(assembler.symbols.value("1#1"), Some(20));
+        assert_eq!(assembler.symbols.value("1#2"), Some(28));
+        assert_eq!(assembler.symbols.value("other.loop"), Some(44));
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("B @1f\n2: HLT"),
+            Err(AssemblerError::new(
+                ErrorKind::UndefinedSymbol {
+                    name: String::from("1f")
+                },
+                Location::new(1, 3)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_raw_data() {
+        let mut assembler = Assembler::new();
+
+        let program = assembler
+            .assemble(
+                ".data\n\
+                 table: .byte #1 #255 (-#1)\n\
+                 .word @table + #1\n\
+                 .code\n\
+                 .word ~#0 @end\n\
+                 end: .byte #7",
+            )
+            .unwrap();
+
+        assert_eq!(program, vec![255, 255, 255, 255, 0, 0, 0, 8, 7]);
+        assert_eq!(assembler.read_only_data, vec![1, 255, 255, 0, 0, 0, 1]);
+
+        // Bytes that add up to whole words keep the code aligned
+        let program = Assembler::new()
+            .assemble(".byte #1 #2\n.byte #3 #4\nHLT")
+            .unwrap();
+        assert_eq!(program, vec![1, 2, 3, 4, 5, 0, 0, 0]);
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble("LOAD $1 #3\n.byte #1\nLOAD $1 #5\nHLT"),
+            Err(AssemblerError::new(
+                ErrorKind::UnalignedCode { address: 5 },
+                Location::new(3, 1)
+            ))
+        );
+        let error = Assembler::new().assemble(".byte #1\nend:").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnalignedCode { address: 1 });
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.assemble(".byte #256"),
+            Err(AssemblerError::new(
+                ErrorKind::OperandOutOfRange {
+                    value: 256,
+                    bits: 8
+                },
+                Location::new(1, 1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let error = |source: &str| Assembler::new().assemble(source).unwrap_err().to_string();
+
+        assert_eq!(error("LAOD $0 #1"), "1:1: unknown instruction `LAOD`");
+        assert_eq!(error("LOAD $0 !"), "1:9: unexpected character `!`");
+        assert_eq!(
+            error("LOAD $0 #99999999999"),
+            "1:9: `#99999999999` is not a number that fits in 32 bits"
+        );
+        assert_eq!(
+            error(".asciiz \"hello"),
+            "1:9: string is missing its closing `\"`"
+        );
+        assert_eq!(error("HLT\n^"), "2:1: unexpected character `^`");
+
+        // Whatever the input, assembling it fails or succeeds without panicking
+        let alphabet: Vec<char> = "LOADHLTINCBli $#@.:\"()+-*/~<>,0123456789\n;!=_"
+            .chars()
+            .collect();
+        let mut seed: u32 = 11;
+        for length in 0..400 {
+            let source: String = (0..length % 40)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    alphabet[(seed >> 16) as usize % alphabet.len()]
+                })
+                .collect();
+
+            let _ = Assembler::new().assemble(&source);
+        }
+    }
 }
@@ -1,6 +1,7 @@
-use core::panic;
+use super::{error::ErrorKind, symbol::SymbolTable, Token};
 
-use super::Token;
+/// Width of an immediate operand, LOAD zero extends it into the register
+const IMMEDIATE_BITS: u32 = 16;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum AssemblerToken {
@@ -13,12 +14,7 @@ pub enum AssemblerToken {
     },
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct LabelDeclaration {
-    pub assembler_instruction: AssemblerInstruction,
-}
-
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub directive: Option<Token>,
@@ -29,29 +25,65 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, ErrorKind> {
         let mut result = vec![];
 
         match &self.opcode {
             Some(Token::Op { code }) => result.push(*code as u8),
-            Some(Token::Directive { value }) => {}
             e => {
                 panic!("Expected Opcode, found: {:#?}", e)
             }
         }
 
-        for operand in vec![&self.operand_one, &self.operand_two, &self.operand_three] {
-            match operand {
-                Some(t) => self.get_operand(t, &mut result),
-                None => {}
-            }
+        for t in self.operands() {
+            self.get_operand(t, symbols, &mut result)?;
         }
 
         while result.len() < 4 {
             result.push(0)
         }
 
-        result
+        Ok(result)
+    }
+
+    /// Number of bytes `.byte` or `.word` emits, 0 for anything else
+    pub fn data_size(&self) -> u32 {
+        match self.get_directive_name() {
+            Some("byte") => self.operands().count() as u32,
+            Some("word") => 4 * self.operands().count() as u32,
+            _ => 0,
+        }
+    }
+
+    /// Encodes the operands of `.byte` or `.word` as big-endian values
+    pub fn data_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, ErrorKind> {
+        let bits = match self.get_directive_name() {
+            Some("byte") => 8,
+            _ => 32,
+        };
+
+        if !self.has_operands() {
+            return Err(ErrorKind::UnexpectedToken { found: Token::EOF });
+        }
+
+        let mut result = vec![];
+        for t in self.operands() {
+            let value = evaluate_operand(t, symbols)?;
+
+            // Values can be given signed or unsigned
+            if value < -(1 << (bits - 1)) || value >= 1 << bits {
+                return Err(ErrorKind::OperandOutOfRange { value, bits });
+            }
+
+            let bytes = (value as u32).to_be_bytes();
+            result.extend_from_slice(&bytes[4 - bits as usize / 8..]);
+        }
+
+        Ok(result)
+    }
+
+    pub fn is_opcode(&self) -> bool {
+        self.opcode.is_some()
     }
 
     pub fn is_directive(&self) -> bool {
@@ -59,50 +91,65 @@ impl AssemblerInstruction {
     }
 
     pub fn has_operands(&self) -> bool {
-        for operand in vec![&self.operand_one, &self.operand_two, &self.operand_three] {
-            if operand.is_some() {
-                return true;
-            }
-        }
-        return false;
+        self.operands().next().is_some()
+    }
+
+    pub fn operands(&self) -> impl Iterator<Item = &Token> {
+        [&self.operand_one, &self.operand_two, &self.operand_three]
+            .into_iter()
+            .flatten()
     }
 
     pub fn get_directive_name(&self) -> Option<&str> {
         match &self.directive {
-            Some(d) => match d {
-                Token::Directive { value: name } => Some(name.as_ref()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::Directive { value: name }) => Some(name.as_ref()),
+            _ => None,
         }
     }
 
     pub fn get_string_content(&self) -> Option<&str> {
-        if let Some(v) = &self.operand_one {
-            match &v {
-                Token::StringOperand { operand: s } => Some(s.as_str()),
-                _ => None,
-            }
-        } else {
-            None
+        match &self.operand_one {
+            Some(Token::StringOperand { operand: s }) => Some(s.as_str()),
+            _ => None,
         }
     }
 
-    fn get_operand(&self, t: &Token, result: &mut Vec<u8>) {
+    fn get_operand(
+        &self,
+        t: &Token,
+        symbols: &SymbolTable,
+        result: &mut Vec<u8>,
+    ) -> Result<(), ErrorKind> {
         match t {
             Token::Register { register } => result.push(*register as u8),
-            Token::IntOperand { operand } => {
-                let converted = *operand as u16;
+            _ => {
+                let value = evaluate_operand(t, symbols)?;
+                let converted = u16::try_from(value).map_err(|_| ErrorKind::OperandOutOfRange {
+                    value,
+                    bits: IMMEDIATE_BITS,
+                })?;
                 let byte_one = converted;
                 let byte_two = converted >> 8;
                 result.push(byte_two as u8);
                 result.push(byte_one as u8);
             }
-
-            e => {
-                panic!("Expected Register or Integer operand, found {e}")
-            }
         }
+
+        Ok(())
+    }
+}
+
+/// Evaluates an operand that represents a constant value
+pub fn evaluate_operand(t: &Token, symbols: &SymbolTable) -> Result<i64, ErrorKind> {
+    match t {
+        Token::IntOperand { operand } => Ok(*operand as i64),
+        Token::Label { name } | Token::Identifier { name } => symbols
+            .value(name)
+            .ok_or_else(|| ErrorKind::UndefinedSymbol { name: name.clone() }),
+        Token::Expression { expr } => expr.evaluate(symbols),
+        found => Err(ErrorKind::InvalidOperand {
+            found: found.clone(),
+        }),
     }
 }
 
@@ -123,7 +170,7 @@ mod tests {
             label: None,
         };
 
-        assert_eq!(ai.to_bytes(), [0, 10, 1, 244])
+        assert_eq!(ai.to_bytes(&SymbolTable::new()), Ok(vec![0, 10, 1, 244]))
     }
 
     #[test]
@@ -139,6 +186,28 @@ mod tests {
             label: None,
         };
 
-        assert_eq!(ai.to_bytes(), [1, 0, 10, 5])
+        assert_eq!(ai.to_bytes(&SymbolTable::new()), Ok(vec![1, 0, 10, 5]))
+    }
+
+    #[test]
+    fn test_assemblerinstruction_operand_out_of_range() {
+        let ai = AssemblerInstruction {
+            opcode: Some(Token::Op {
+                code: crate::instruction::Opcode::LOAD,
+            }),
+            operand_one: Some(Token::Register { register: 0 }),
+            operand_two: Some(Token::IntOperand { operand: 70000 }),
+            operand_three: None,
+            directive: None,
+            label: None,
+        };
+
+        assert_eq!(
+            ai.to_bytes(&SymbolTable::new()),
+            Err(ErrorKind::OperandOutOfRange {
+                value: 70000,
+                bits: 16
+            })
+        )
     }
 }
@@ -0,0 +1,153 @@
+use super::{error::ErrorKind, Location, Token};
+
+/// One open `.if` block
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conditional {
+    /// Whether the statements currently being read are assembled
+    active: bool,
+    /// Whether a branch of this block has been taken, or can never be taken
+    /// because the surrounding block is inactive
+    taken: bool,
+    seen_else: bool,
+    pub directive: String,
+    pub location: Location,
+}
+
+/// Tracks nested `.if`/`.elif`/`.else`/`.endif` blocks
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ConditionalStack {
+    blocks: Vec<Conditional>,
+}
+
+impl ConditionalStack {
+    pub fn new() -> ConditionalStack {
+        ConditionalStack { blocks: vec![] }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.active)
+    }
+
+    /// Opens a block, `condition` is `None` when the block is inside an
+    /// inactive block and its condition was never evaluated
+    pub fn push(&mut self, directive: &str, condition: Option<bool>, location: Location) {
+        let condition = condition.filter(|_| self.is_active());
+
+        self.blocks.push(Conditional {
+            active: condition == Some(true),
+            taken: condition != Some(false),
+            seen_else: false,
+            directive: String::from(directive),
+            location,
+        });
+    }
+
+    /// Whether the condition of an `.elif` at this point has to be evaluated
+    pub fn elif_needs_condition(&self) -> bool {
+        self.blocks
+            .last()
+            .is_some_and(|block| !block.taken && !block.seen_else)
+    }
+
+    pub fn elif(&mut self, condition: Option<bool>) -> Result<(), ErrorKind> {
+        let block = self.open_block("elif")?;
+        let active = !block.taken && condition == Some(true);
+
+        block.active = active;
+        block.taken |= active;
+
+        Ok(())
+    }
+
+    pub fn else_branch(&mut self) -> Result<(), ErrorKind> {
+        let block = self.open_block("else")?;
+
+        block.active = !block.taken;
+        block.taken = true;
+        block.seen_else = true;
+
+        Ok(())
+    }
+
+    pub fn end(&mut self) -> Result<(), ErrorKind> {
+        match self.blocks.pop() {
+            Some(_) => Ok(()),
+            None => Err(unexpected_directive("endif")),
+        }
+    }
+
+    /// The innermost block that is still open
+    pub fn innermost(&self) -> Option<&Conditional> {
+        self.blocks.last()
+    }
+
+    fn open_block(&mut self, directive: &str) -> Result<&mut Conditional, ErrorKind> {
+        match self.blocks.last_mut() {
+            Some(block) if !block.seen_else => Ok(block),
+            _ => Err(unexpected_directive(directive)),
+        }
+    }
+}
+
+fn unexpected_directive(directive: &str) -> ErrorKind {
+    ErrorKind::UnexpectedToken {
+        found: Token::Directive {
+            value: String::from(directive),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_elif_else() {
+        let mut stack = ConditionalStack::new();
+
+        stack.push("if", Some(false), Location::new(1, 1));
+        assert!(!stack.is_active());
+        assert!(stack.elif_needs_condition());
+
+        stack.elif(Some(true)).unwrap();
+        assert!(stack.is_active());
+        assert!(!stack.elif_needs_condition());
+
+        stack.elif(None).unwrap();
+        assert!(!stack.is_active());
+
+        stack.else_branch().unwrap();
+        assert!(!stack.is_active());
+
+        stack.end().unwrap();
+        assert!(stack.is_active());
+    }
+
+    #[test]
+    fn test_nested_in_inactive_block() {
+        let mut stack = ConditionalStack::new();
+
+        stack.push("if", Some(false), Location::new(1, 1));
+        stack.push("ifdef", None, Location::new(2, 1));
+        assert!(!stack.elif_needs_condition());
+
+        stack.else_branch().unwrap();
+        assert!(!stack.is_active());
+
+        stack.end().unwrap();
+        stack.else_branch().unwrap();
+        assert!(stack.is_active());
+    }
+
+    #[test]
+    fn test_unmatched_directives() {
+        let mut stack = ConditionalStack::new();
+        assert!(stack.else_branch().is_err());
+        assert!(stack.end().is_err());
+
+        stack.push("if", Some(true), Location::new(1, 1));
+        stack.else_branch().unwrap();
+        assert_eq!(stack.elif(Some(true)), Err(unexpected_directive("elif")));
+        assert_eq!(stack.innermost().unwrap().location, Location::new(1, 1));
+    }
+}
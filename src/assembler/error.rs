@@ -0,0 +1,239 @@
+use std::fmt::Display;
+
+use super::{register::REGISTER_COUNT, Location, Token};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssemblerError {
+    pub kind: ErrorKind,
+    pub location: Location,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ErrorKind {
+    UnexpectedToken {
+        found: Token,
+    },
+    UnknownInstruction {
+        name: String,
+    },
+    UnknownDirective {
+        name: String,
+    },
+    UndefinedSymbol {
+        name: String,
+    },
+    DuplicateSymbol {
+        name: String,
+    },
+    InvalidOperand {
+        found: Token,
+    },
+    /// An instruction or label in the code section that raw data before it
+    /// moved off a 4-byte boundary
+    UnalignedCode {
+        address: u32,
+    },
+    ArithmeticOverflow,
+    DivisionByZero,
+    OperandOutOfRange {
+        value: i64,
+        bits: u32,
+    },
+    ReservedRegister {
+        register: i32,
+    },
+    UnknownRegister {
+        name: String,
+    },
+    RegisterOutOfRange {
+        register: i32,
+    },
+    UnterminatedMacro {
+        name: String,
+    },
+    /// A macro named like a pseudo-instruction, invoking it would be the
+    /// pseudo-instruction
+    ReservedName {
+        name: String,
+    },
+    MacroArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    MacroRecursionLimit {
+        name: String,
+        limit: usize,
+    },
+    UnterminatedBlock {
+        directive: String,
+    },
+    InvalidRepeatCount {
+        count: i64,
+    },
+    /// `.rept` blocks expanded to more tokens than `MAX_REPEAT_TOKENS`
+    RepeatLimit {
+        limit: usize,
+    },
+    IncludeNotFound {
+        path: String,
+    },
+    IncludeCycle {
+        path: String,
+    },
+    Io {
+        path: String,
+        message: String,
+    },
+}
+
+/// Text that doesn't form a token
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexError {
+    UnexpectedCharacter {
+        character: char,
+    },
+    /// A `#` that isn't followed by a number, optionally negative, that fits
+    /// in 32 bits
+    InvalidNumber {
+        text: String,
+    },
+    UnterminatedString,
+}
+
+impl LexError {
+    /// The text that was rejected
+    pub fn text(&self) -> String {
+        match self {
+            LexError::UnexpectedCharacter { character } => character.to_string(),
+            LexError::InvalidNumber { text } => text.clone(),
+            LexError::UnterminatedString => String::from("\""),
+        }
+    }
+}
+
+impl AssemblerError {
+    pub fn new(kind: ErrorKind, location: Location) -> AssemblerError {
+        AssemblerError { kind, location }
+    }
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Errors that happen before anything is read have no position
+        if self.location.line == 0 {
+            write!(f, "{}", self.kind)?;
+        } else {
+            write!(f, "{}: {}", self.location, self.kind)?;
+        }
+
+        for expansion in self.location.expansions() {
+            match expansion.macro_name.strip_prefix('.') {
+                Some(directive) => write!(
+                    f,
+                    "\n    in `.{}` block starting at {}",
+                    directive, expansion.invocation
+                )?,
+                None => write!(
+                    f,
+                    "\n    in expansion of macro `{}` invoked at {}",
+                    expansion.macro_name, expansion.invocation
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter { character } => {
+                write!(f, "unexpected character `{}`", character.escape_default())
+            }
+            LexError::InvalidNumber { text } => {
+                write!(f, "`{text}` is not a number that fits in 32 bits")
+            }
+            LexError::UnterminatedString => write!(f, "string is missing its closing `\"`"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::UnexpectedToken { found: Token::EOF } => {
+                write!(f, "unexpected end of input")
+            }
+            ErrorKind::UnexpectedToken {
+                found: Token::Invalid { error },
+            }
+            | ErrorKind::InvalidOperand {
+                found: Token::Invalid { error },
+            } => write!(f, "{error}"),
+            ErrorKind::UnexpectedToken { found } => write!(f, "unexpected token `{found}`"),
+            ErrorKind::UnknownInstruction { name } => write!(f, "unknown instruction `{name}`"),
+            ErrorKind::UnknownDirective { name } => write!(f, "unknown directive `.{name}`"),
+            ErrorKind::UndefinedSymbol { name } => write!(f, "undefined symbol `{name}`"),
+            ErrorKind::DuplicateSymbol { name } => {
+                write!(f, "symbol `{name}` is already defined")
+            }
+            ErrorKind::InvalidOperand { found } => write!(f, "invalid operand `{found}`"),
+            ErrorKind::UnalignedCode { address } => write!(
+                f,
+                "address {address} is not on a 4-byte boundary, raw data before an instruction or \
+                 label in the code section has to be a multiple of 4 bytes long"
+            ),
+            ErrorKind::ArithmeticOverflow => write!(f, "arithmetic overflow in expression"),
+            ErrorKind::DivisionByZero => write!(f, "division by zero in expression"),
+            ErrorKind::OperandOutOfRange { value, bits } => {
+                write!(f, "value {value} does not fit in a {bits}-bit operand")
+            }
+            ErrorKind::UnknownRegister { name } => write!(f, "unknown register `${name}`"),
+            ErrorKind::RegisterOutOfRange { register } => write!(
+                f,
+                "register ${register} does not exist, registers go from $0 to ${}",
+                REGISTER_COUNT - 1
+            ),
+            ErrorKind::ReservedRegister { register } => write!(
+                f,
+                "register ${register} is reserved for pseudo-instructions"
+            ),
+            ErrorKind::UnterminatedMacro { name } => {
+                write!(f, "macro `{name}` is missing its `.endm`")
+            }
+            ErrorKind::MacroArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro `{name}` takes {expected} argument(s) but {found} were given"
+            ),
+            ErrorKind::ReservedName { name } => {
+                write!(f, "`{name}` is a reserved pseudo-instruction name")
+            }
+            ErrorKind::MacroRecursionLimit { name, limit } => write!(
+                f,
+                "expanding macro `{name}` exceeded the nesting limit of {limit}"
+            ),
+            ErrorKind::UnterminatedBlock { directive } => {
+                write!(f, "`.{directive}` block is never closed")
+            }
+            ErrorKind::InvalidRepeatCount { count } => {
+                write!(f, "cannot repeat a block {count} times")
+            }
+            ErrorKind::RepeatLimit { limit } => {
+                write!(f, "`.rept` blocks expand to more than {limit} tokens")
+            }
+            ErrorKind::IncludeNotFound { path } => write!(f, "could not find `{path}` to include"),
+            ErrorKind::IncludeCycle { path } => {
+                write!(f, "`{path}` includes itself")
+            }
+            ErrorKind::Io { path, message } => write!(f, "could not read `{path}`: {message}"),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::Display;
+
+use super::{error::ErrorKind, symbol::SymbolTable, Operator};
+
+/// A constant expression used as an immediate operand, it is evaluated
+/// during assembly once every symbol it refers to has a value
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Number(i64),
+    Label(String),
+    Symbol(String),
+    Unary {
+        operator: Operator,
+        operand: Box<Expr>,
+    },
+    Binary {
+        operator: Operator,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+}
+
+impl Expr {
+    pub fn evaluate(&self, symbols: &SymbolTable) -> Result<i64, ErrorKind> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Label(name) | Expr::Symbol(name) => symbols
+                .value(name)
+                .ok_or_else(|| ErrorKind::UndefinedSymbol { name: name.clone() }),
+            Expr::Unary { operator, operand } => {
+                let value = operand.evaluate(symbols)?;
+                match operator {
+                    Operator::Minus => value.checked_neg().ok_or(ErrorKind::ArithmeticOverflow),
+                    Operator::Tilde => Ok(!value),
+                    _ => unreachable!("{operator} is not a unary operator"),
+                }
+            }
+            Expr::Binary { operator, lhs, rhs } => {
+                let lhs = lhs.evaluate(symbols)?;
+                let rhs = rhs.evaluate(symbols)?;
+                apply_binary(*operator, lhs, rhs)
+            }
+        }
+    }
+}
+
+fn apply_binary(operator: Operator, lhs: i64, rhs: i64) -> Result<i64, ErrorKind> {
+    let result = match operator {
+        Operator::Plus => lhs.checked_add(rhs),
+        Operator::Minus => lhs.checked_sub(rhs),
+        Operator::Star => lhs.checked_mul(rhs),
+        Operator::Slash | Operator::Percent if rhs == 0 => return Err(ErrorKind::DivisionByZero),
+        Operator::Slash => lhs.checked_div(rhs),
+        Operator::Percent => lhs.checked_rem(rhs),
+        Operator::ShiftLeft => u32::try_from(rhs)
+            .ok()
+            .and_then(|shift| lhs.checked_shl(shift))
+            // checked_shl only catches too large shifts, not bits shifted out
+            .filter(|result| result >> rhs == lhs),
+        Operator::ShiftRight => u32::try_from(rhs)
+            .ok()
+            .and_then(|shift| lhs.checked_shr(shift)),
+        Operator::Ampersand => Some(lhs & rhs),
+        Operator::Pipe => Some(lhs | rhs),
+        Operator::Tilde => unreachable!("~ is not a binary operator"),
+    };
+
+    result.ok_or(ErrorKind::ArithmeticOverflow)
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "#{n}"),
+            Expr::Label(name) => write!(f, "@{name}"),
+            Expr::Symbol(name) => write!(f, "{name}"),
+            Expr::Unary { operator, operand } => write!(f, "{operator}{operand}"),
+            Expr::Binary { operator, lhs, rhs } => write!(f, "({lhs} {operator} {rhs})"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbol::{Symbol, SymbolType};
+
+    fn number(n: i64) -> Box<Expr> {
+        Box::new(Expr::Number(n))
+    }
+
+    #[test]
+    fn test_evaluate_binary() {
+        let symbols = SymbolTable::new();
+        let test_cases = [
+            (Operator::Plus, 6, 3, 9),
+            (Operator::Minus, 6, 3, 3),
+            (Operator::Star, 6, 3, 18),
+            (Operator::Slash, 6, 3, 2),
+            (Operator::Percent, 7, 3, 1),
+            (Operator::ShiftLeft, 1, 4, 16),
+            (Operator::ShiftRight, 16, 2, 4),
+            (Operator::Ampersand, 6, 3, 2),
+            (Operator::Pipe, 6, 3, 7),
+        ];
+
+        for (operator, lhs, rhs, expected) in test_cases {
+            let expr = Expr::Binary {
+                operator,
+                lhs: number(lhs),
+                rhs: number(rhs),
+            };
+            assert_eq!(expr.evaluate(&symbols), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_label_arithmetic() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new(String::from("start"), 4, SymbolType::Label));
+        symbols.add_symbol(Symbol::new(String::from("end"), 24, SymbolType::Label));
+
+        let expr = Expr::Binary {
+            operator: Operator::Minus,
+            lhs: Box::new(Expr::Label(String::from("end"))),
+            rhs: Box::new(Expr::Label(String::from("start"))),
+        };
+
+        assert_eq!(expr.evaluate(&symbols), Ok(20));
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let symbols = SymbolTable::new();
+
+        let expr = Expr::Binary {
+            operator: Operator::Slash,
+            lhs: number(1),
+            rhs: number(0),
+        };
+        assert_eq!(expr.evaluate(&symbols), Err(ErrorKind::DivisionByZero));
+
+        let expr = Expr::Binary {
+            operator: Operator::ShiftLeft,
+            lhs: number(i64::MAX),
+            rhs: number(1),
+        };
+        assert_eq!(expr.evaluate(&symbols), Err(ErrorKind::ArithmeticOverflow));
+
+        let expr = Expr::Symbol(String::from("missing"));
+        assert_eq!(
+            expr.evaluate(&symbols),
+            Err(ErrorKind::UndefinedSymbol {
+                name: String::from("missing")
+            })
+        );
+    }
+}
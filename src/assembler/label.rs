@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use super::{error::ErrorKind, Location};
+
+/// Turns the label names written in the source into unique symbol names.
+///
+/// Local labels start with a `.` and belong to the closest global label
+/// declared before them, so `.loop` under `main` becomes `main.loop`.
+/// Numeric labels like `1:` can be declared any number of times, `@1b` refers
+/// to the closest one before the reference and `@1f` to the closest one after.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LabelScopes {
+    /// The global label local labels are currently declared under
+    scope: String,
+    /// How many times each numeric label has been declared so far
+    numeric: HashMap<String, usize>,
+    /// Forward references to numeric labels, checked once everything is read
+    forward: Vec<ForwardReference>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct ForwardReference {
+    number: String,
+    index: usize,
+    location: Location,
+}
+
+impl LabelScopes {
+    pub fn new() -> LabelScopes {
+        LabelScopes::default()
+    }
+
+    /// Returns the symbol name for a label declaration
+    pub fn declare(&mut self, name: &str) -> String {
+        if is_numeric(name) {
+            let count = self.numeric.entry(String::from(name)).or_insert(0);
+            *count += 1;
+            return numeric_symbol(name, *count - 1);
+        }
+
+        if name.starts_with('.') {
+            return format!("{}{}", self.scope, name);
+        }
+
+        // Labels made unique by a macro expansion are internal to it and
+        // don't change the scope of the code around the invocation
+        if !name.contains('#') {
+            self.scope = String::from(name);
+        }
+
+        String::from(name)
+    }
+
+    /// Returns the symbol name a label reference refers to
+    pub fn reference(&mut self, name: &str, location: &Location) -> Result<String, ErrorKind> {
+        if let Some(number) = name.strip_suffix('b').filter(|n| is_numeric(n)) {
+            return match self.numeric.get(number) {
+                Some(count) => Ok(numeric_symbol(number, count - 1)),
+                None => Err(ErrorKind::UndefinedSymbol {
+                    name: String::from(name),
+                }),
+            };
+        }
+
+        if let Some(number) = name.strip_suffix('f').filter(|n| is_numeric(n)) {
+            let index = self.numeric.get(number).copied().unwrap_or(0);
+            self.forward.push(ForwardReference {
+                number: String::from(number),
+                index,
+                location: location.clone(),
+            });
+
+            return Ok(numeric_symbol(number, index));
+        }
+
+        if name.starts_with('.') {
+            return Ok(format!("{}{}", self.scope, name));
+        }
+
+        Ok(String::from(name))
+    }
+
+    /// The first forward reference to a numeric label that was never declared
+    pub fn unresolved(&self) -> Option<(ErrorKind, Location)> {
+        self.forward
+            .iter()
+            .find(|r| self.numeric.get(&r.number).copied().unwrap_or(0) <= r.index)
+            .map(|r| {
+                (
+                    ErrorKind::UndefinedSymbol {
+                        name: format!("{}f", r.number),
+                    },
+                    r.location.clone(),
+                )
+            })
+    }
+}
+
+pub fn is_numeric(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+fn numeric_symbol(number: &str, index: usize) -> String {
+    format!("{number}#{index}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_labels() {
+        let mut labels = LabelScopes::new();
+        let location = Location::new(1, 1);
+
+        assert_eq!(labels.declare("main"), "main");
+        assert_eq!(labels.declare(".loop"), "main.loop");
+        assert_eq!(
+            labels.reference(".loop", &location),
+            Ok(String::from("main.loop"))
+        );
+
+        assert_eq!(labels.declare("other"), "other");
+        assert_eq!(labels.declare("again#3"), "again#3");
+        assert_eq!(
+            labels.reference(".loop", &location),
+            Ok(String::from("other.loop"))
+        );
+        assert_eq!(
+            labels.reference("main.loop", &location),
+            Ok(String::from("main.loop"))
+        );
+    }
+
+    #[test]
+    fn test_numeric_labels() {
+        let mut labels = LabelScopes::new();
+        let location = Location::new(1, 1);
+
+        assert_eq!(
+            labels.reference("1b", &location),
+            Err(ErrorKind::UndefinedSymbol {
+                name: String::from("1b")
+            })
+        );
+        assert_eq!(labels.reference("1f", &location), Ok(String::from("1#0")));
+        assert_eq!(labels.declare("1"), "1#0");
+        assert_eq!(labels.reference("1b", &location), Ok(String::from("1#0")));
+        assert_eq!(labels.reference("1f", &location), Ok(String::from("1#1")));
+        assert_eq!(labels.declare("1"), "1#1");
+        assert_eq!(labels.unresolved(), None);
+
+        labels.reference("2f", &Location::new(4, 2)).unwrap();
+        assert_eq!(
+            labels.unresolved(),
+            Some((
+                ErrorKind::UndefinedSymbol {
+                    name: String::from("2f")
+                },
+                Location::new(4, 2)
+            ))
+        );
+    }
+}
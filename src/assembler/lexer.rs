@@ -3,7 +3,7 @@ use std::str::FromStr;
 
 use crate::instruction::Opcode;
 
-use super::Token;
+use super::{error::LexError, pseudo::PseudoOpcode, Location, Operator, Token};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Lexer {
@@ -11,23 +11,44 @@ pub struct Lexer {
     current: usize,
     next: usize,
     char: char,
+    line: usize,
+    column: usize,
+    newline: bool,
+    token_location: Location,
+    token_starts_line: bool,
+    /// Whether the last lexed token declared a label, what follows it on the
+    /// same line is in mnemonic position
+    after_label: bool,
 }
 
 impl Lexer {
     pub fn new(source: &str) -> Lexer {
-        let source = source.chars().collect();
-        let mut l = Lexer {
+        let source: Vec<char> = source.chars().collect();
+        let char = source.first().copied().unwrap_or('\0');
+
+        Lexer {
             source,
             current: 0,
             next: 1,
-            char: '\0',
-        };
-
-        l.char = l.source[l.current];
-        l
+            char,
+            line: 1,
+            column: 1,
+            newline: true,
+            token_location: Location::default(),
+            token_starts_line: true,
+            after_label: false,
+        }
     }
 
     fn read(&mut self) {
+        if self.char == '\n' {
+            self.line += 1;
+            self.column = 1;
+            self.newline = true;
+        } else {
+            self.column += 1;
+        }
+
         if self.next >= self.source.len() {
             self.char = '\0';
         } else {
@@ -38,87 +59,170 @@ impl Lexer {
         self.next = self.current + 1;
     }
 
-    pub fn lex(&mut self) -> Token {
-        while self.char.is_whitespace() {
-            self.read()
+    fn peek_char(&self) -> char {
+        self.source.get(self.next).copied().unwrap_or('\0')
+    }
+
+    /// Location of the first character of the last lexed token
+    pub fn token_location(&self) -> Location {
+        self.token_location.clone()
+    }
+
+    /// Whether the last lexed token is the first one on its line
+    pub fn token_starts_line(&self) -> bool {
+        self.token_starts_line
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            while self.char.is_whitespace() {
+                self.read()
+            }
+
+            if self.char == '/' && self.peek_char() == '/' {
+                while self.char != '\n' && self.char != '\0' {
+                    self.read()
+                }
+            } else {
+                break;
+            }
         }
+    }
+
+    pub fn lex(&mut self) -> Token {
+        self.skip_whitespace_and_comments();
+
+        self.token_location = Location::new(self.line, self.column);
+        self.token_starts_line = self.newline;
+        self.newline = false;
 
-        match self.char {
+        let token = match self.char {
             '#' => self.lex_int_operand(),
             '$' => self.lex_register(),
             '@' => self.lex_label(),
             '.' => self.lex_directives(),
             '"' => self.lex_string(),
+            '(' => self.lex_single(Token::LeftParen),
+            ')' => self.lex_single(Token::RightParen),
+            ',' => self.lex_single(Token::Comma),
+            '+' => self.lex_operator(Operator::Plus),
+            '-' => self.lex_operator(Operator::Minus),
+            '*' => self.lex_operator(Operator::Star),
+            '/' => self.lex_operator(Operator::Slash),
+            '%' => self.lex_operator(Operator::Percent),
+            '&' => self.lex_operator(Operator::Ampersand),
+            '|' => self.lex_operator(Operator::Pipe),
+            '~' => self.lex_operator(Operator::Tilde),
+            '<' if self.peek_char() == '<' => {
+                self.read();
+                self.lex_operator(Operator::ShiftLeft)
+            }
+            '>' if self.peek_char() == '>' => {
+                self.read();
+                self.lex_operator(Operator::ShiftRight)
+            }
             _ if self.char.is_alphabetic() => self.parse_opcode(),
+            _ if self.char.is_ascii_digit() => self.lex_numeric_label(),
             '\0' => Token::EOF,
-            // TODO: Better error handling in lexer
-            _ => unimplemented!(),
-        }
+            character => self.lex_single(Token::Invalid {
+                error: LexError::UnexpectedCharacter { character },
+            }),
+        };
+        self.after_label = matches!(token, Token::LabelDeclaration { .. });
+
+        token
+    }
+
+    fn lex_single(&mut self, token: Token) -> Token {
+        self.read();
+        token
+    }
+
+    fn lex_operator(&mut self, operator: Operator) -> Token {
+        self.lex_single(Token::Operator { operator })
     }
 
     fn parse_opcode(&mut self) -> Token {
         let mut s = String::new();
 
-        while self.char.is_alphabetic() || self.char == '_' || self.char == '-' {
+        while self.char.is_alphanumeric() || self.char == '_' {
             s.push(self.char);
             self.read();
         }
 
         if self.char == ':' {
             self.read();
-            let token = Token::LabelDeclaration { value: s };
-
-            token
+            Token::LabelDeclaration { value: s }
+        } else if let Ok(opcode) = Opcode::from_str(&s.to_lowercase()) {
+            Token::Op { code: opcode }
+        } else if let Some(code) = self
+            .in_mnemonic_position()
+            .then(|| PseudoOpcode::from_str(&s.to_lowercase()).ok())
+            .flatten()
+        {
+            // Elsewhere these are ordinary names, like in `.equ b #1`
+            Token::PseudoOp { code }
         } else {
-            if let Ok(opcode) = Opcode::from_str(&s.to_lowercase()) {
-                let token = Token::Op { code: opcode };
-
-                token
-            } else {
-                panic!("Unknown Opcode: {}", s)
-            }
+            Token::Identifier { name: s }
         }
     }
 
+    /// Whether the token being lexed is where an instruction's mnemonic goes,
+    /// first on its line or right after a label declaration
+    fn in_mnemonic_position(&self) -> bool {
+        self.token_starts_line || self.after_label
+    }
+
+    /// Lexes `$12` as a register number and `$sp` as a register name, which
+    /// the parser resolves
     fn lex_register(&mut self) -> Token {
         let mut s = String::new();
         self.read();
 
-        while self.char.is_numeric() {
+        while self.char.is_alphanumeric() || self.char == '_' {
             s.push(self.char);
             self.read()
         }
 
-        if let Ok(register) = s.parse() {
-            Token::Register { register }
-        } else {
-            panic!("Could not parse number for register: {}", s)
+        match s.parse() {
+            Ok(register) => Token::Register { register },
+            Err(_) => Token::RegisterName { name: s },
         }
     }
 
+    /// Lexes `#12` or `#-12`, whether a negative value fits is up to the
+    /// instruction it is used in
     fn lex_int_operand(&mut self) -> Token {
         let mut s = String::new();
         self.read();
 
+        if self.char == '-' {
+            s.push(self.char);
+            self.read()
+        }
+
         while self.char.is_numeric() {
             s.push(self.char);
             self.read()
         }
 
-        if let Ok(int_operand) = s.parse() {
-            Token::IntOperand {
-                operand: int_operand,
-            }
-        } else {
-            panic!("Could not parse number for register: {}", s)
+        match s.parse() {
+            Ok(operand) => Token::IntOperand { operand },
+            Err(_) => Token::Invalid {
+                error: LexError::InvalidNumber {
+                    text: format!("#{s}"),
+                },
+            },
         }
     }
 
+    /// Lexes a label reference, besides plain names this can be a local label
+    /// like `@.loop` or `@main.loop`, or a numeric label like `@1b`
     fn lex_label(&mut self) -> Token {
         self.read();
         let mut s = String::new();
 
-        while self.char.is_alphabetic() || self.char == '_' || self.char == '-' {
+        while self.char.is_alphanumeric() || self.char == '_' || self.char == '.' {
             s.push(self.char);
             self.read()
         }
@@ -126,16 +230,41 @@ impl Lexer {
         Token::Label { name: s }
     }
 
+    /// Lexes a directive, or the declaration of a local label like `.loop:`
     fn lex_directives(&mut self) -> Token {
         self.read();
         let mut s = String::new();
 
-        while self.char.is_alphabetic() {
+        while self.char.is_alphanumeric() || self.char == '_' {
+            s.push(self.char);
+            self.read()
+        }
+
+        if self.char == ':' {
+            self.read();
+            Token::LabelDeclaration {
+                value: format!(".{s}"),
+            }
+        } else {
+            Token::Directive { value: s }
+        }
+    }
+
+    /// Lexes the declaration of a numeric label like `1:`
+    fn lex_numeric_label(&mut self) -> Token {
+        let mut s = String::new();
+
+        while self.char.is_ascii_digit() {
             s.push(self.char);
             self.read()
         }
 
-        Token::Directive { value: s }
+        if self.char == ':' {
+            self.read();
+            Token::LabelDeclaration { value: s }
+        } else {
+            Token::Identifier { name: s }
+        }
     }
 
     fn lex_string(&mut self) -> Token {
@@ -143,11 +272,16 @@ impl Lexer {
         let mut s = String::new();
 
         while self.char != '"' {
+            if self.char == '\0' {
+                return Token::Invalid {
+                    error: LexError::UnterminatedString,
+                };
+            }
+
             s.push(self.char);
             self.read();
         }
 
-        assert!(self.char == '"');
         self.read();
 
         Token::StringOperand { operand: s }
@@ -169,6 +303,7 @@ impl Iterator for Lexer {
 }
 
 #[cfg(test)]
+#[allow(clippy::while_let_on_iterator)]
 mod tests {
 
     use super::*;
@@ -199,12 +334,62 @@ mod tests {
         run_test(&test_cases)
     }
 
+    #[test]
+    fn test_lex_labels() {
+        let test_cases = [
+            (
+                "loop2:",
+                Token::LabelDeclaration {
+                    value: String::from("loop2"),
+                },
+            ),
+            (
+                ".loop:",
+                Token::LabelDeclaration {
+                    value: String::from(".loop"),
+                },
+            ),
+            (
+                "1:",
+                Token::LabelDeclaration {
+                    value: String::from("1"),
+                },
+            ),
+            (
+                "@main.loop",
+                Token::Label {
+                    name: String::from("main.loop"),
+                },
+            ),
+            (
+                "@1b",
+                Token::Label {
+                    name: String::from("1b"),
+                },
+            ),
+        ];
+
+        run_test(&test_cases)
+    }
+
     #[test]
     fn test_lex_register() {
         let test_cases = [
             ("$10", Token::Register { register: 10 }),
             ("$100", Token::Register { register: 100 }),
             ("$5", Token::Register { register: 5 }),
+            (
+                "$sp",
+                Token::RegisterName {
+                    name: String::from("sp"),
+                },
+            ),
+            (
+                "$t0",
+                Token::RegisterName {
+                    name: String::from("t0"),
+                },
+            ),
         ];
 
         run_test(&test_cases)
@@ -216,6 +401,16 @@ mod tests {
             ("#10", Token::IntOperand { operand: 10 }),
             ("#20", Token::IntOperand { operand: 20 }),
             ("#30", Token::IntOperand { operand: 30 }),
+            ("#-1", Token::IntOperand { operand: -1 }),
+            ("#-2147483648", Token::IntOperand { operand: i32::MIN }),
+            (
+                "#-",
+                Token::Invalid {
+                    error: LexError::InvalidNumber {
+                        text: String::from("#-"),
+                    },
+                },
+            ),
         ];
 
         run_test(&test_cases)
@@ -394,4 +589,85 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_lex_expression() {
+        let lexer = Lexer::new("(@end - @start) << #2 | ~SIZE % #3 & #1 >> #4 * #2 / #1 + #0");
+        let tokens: Vec<Token> = lexer.collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LeftParen,
+                Token::Label {
+                    name: String::from("end")
+                },
+                Token::Operator {
+                    operator: Operator::Minus
+                },
+                Token::Label {
+                    name: String::from("start")
+                },
+                Token::RightParen,
+                Token::Operator {
+                    operator: Operator::ShiftLeft
+                },
+                Token::IntOperand { operand: 2 },
+                Token::Operator {
+                    operator: Operator::Pipe
+                },
+                Token::Operator {
+                    operator: Operator::Tilde
+                },
+                Token::Identifier {
+                    name: String::from("SIZE")
+                },
+                Token::Operator {
+                    operator: Operator::Percent
+                },
+                Token::IntOperand { operand: 3 },
+                Token::Operator {
+                    operator: Operator::Ampersand
+                },
+                Token::IntOperand { operand: 1 },
+                Token::Operator {
+                    operator: Operator::ShiftRight
+                },
+                Token::IntOperand { operand: 4 },
+                Token::Operator {
+                    operator: Operator::Star
+                },
+                Token::IntOperand { operand: 2 },
+                Token::Operator {
+                    operator: Operator::Slash
+                },
+                Token::IntOperand { operand: 1 },
+                Token::Operator {
+                    operator: Operator::Plus
+                },
+                Token::IntOperand { operand: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_comments_and_locations() {
+        let mut lexer = Lexer::new("// setup\nload $0 #1 // comment\n  hlt");
+
+        assert_eq!(lexer.lex(), Token::Op { code: Opcode::LOAD });
+        assert_eq!(lexer.token_location(), Location::new(2, 1));
+        assert!(lexer.token_starts_line());
+
+        assert_eq!(lexer.lex(), Token::Register { register: 0 });
+        assert_eq!(lexer.token_location(), Location::new(2, 6));
+        assert!(!lexer.token_starts_line());
+
+        assert_eq!(lexer.lex(), Token::IntOperand { operand: 1 });
+
+        assert_eq!(lexer.lex(), Token::Op { code: Opcode::HLT });
+        assert_eq!(lexer.token_location(), Location::new(3, 3));
+        assert!(lexer.token_starts_line());
+
+        assert_eq!(lexer.lex(), Token::EOF);
+    }
 }
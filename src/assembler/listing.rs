@@ -0,0 +1,186 @@
+use std::{collections::HashMap, fmt::Display};
+
+use super::{
+    assembler::Section,
+    symbol::{Symbol, SymbolTable, SymbolType},
+    Location,
+};
+
+/// Number of encoded bytes shown on one row of the listing
+const BYTES_PER_ROW: usize = 4;
+
+/// What the assembler made of every statement, together with the symbol
+/// table it ended up with. `Display` renders it as a listing file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    /// Labels ordered by section and address, followed by constants ordered
+    /// by name
+    pub symbols: Vec<ListingSymbol>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListingSymbol {
+    pub symbol: Symbol,
+    /// Section a label was declared in, `None` for constants and for labels
+    /// whose section isn't known
+    pub section: Option<Section>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListingLine {
+    pub section: Section,
+    pub address: u32,
+    /// What the statement added to its section
+    pub bytes: Vec<u8>,
+    pub location: Location,
+    /// The source line the statement was read from
+    pub source: String,
+}
+
+impl Listing {
+    /// `sections` gives the section of every label, both sections start at
+    /// address 0 so a label's address alone doesn't tell where it points
+    pub fn new(
+        lines: Vec<ListingLine>,
+        symbols: &SymbolTable,
+        sections: &HashMap<String, Section>,
+    ) -> Listing {
+        let mut symbols: Vec<ListingSymbol> = symbols
+            .symbols
+            .values()
+            .map(|symbol| ListingSymbol {
+                symbol: symbol.clone(),
+                section: match symbol.symbol_type() {
+                    SymbolType::Label => sections.get(symbol.name()).copied(),
+                    SymbolType::Constant => None,
+                },
+            })
+            .collect();
+        symbols.sort_by(|a, b| {
+            let order = |s: &ListingSymbol| match (s.symbol.symbol_type(), s.section) {
+                (SymbolType::Label, Some(Section::Code)) => (0, s.symbol.value()),
+                (SymbolType::Label, Some(Section::Data)) => (1, s.symbol.value()),
+                (SymbolType::Label, None) => (2, s.symbol.value()),
+                (SymbolType::Constant, _) => (3, 0),
+            };
+
+            order(a)
+                .cmp(&order(b))
+                .then_with(|| a.symbol.name().cmp(b.symbol.name()))
+        });
+
+        Listing { lines, symbols }
+    }
+}
+
+impl Display for Listing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<7} {:<7} {:<11}  {:<6} Source",
+            "Section", "Address", "Bytes", "Line"
+        )?;
+
+        for line in &self.lines {
+            let mut rows = line.bytes.chunks(BYTES_PER_ROW);
+            let line_number = match &line.location.file {
+                Some(file) => format!("{}:{}", file.display(), line.location.line),
+                None => line.location.line.to_string(),
+            };
+
+            writeln!(
+                f,
+                "{:<7} {:04x}    {:<11}  {:<6} {}",
+                line.section,
+                line.address,
+                hex(rows.next().unwrap_or_default()),
+                line_number,
+                line.source
+            )?;
+
+            // Statements that encode to more than a row continue below
+            for (i, row) in rows.enumerate() {
+                let address = line.address as usize + (i + 1) * BYTES_PER_ROW;
+                writeln!(f, "{:<7} {:04x}    {}", "", address, hex(row))?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<24} {:<8} Kind", "Symbol", "Value")?;
+        for ListingSymbol { symbol, section } in &self.symbols {
+            let (value, kind) = match (symbol.symbol_type(), section) {
+                (SymbolType::Label, Some(section)) => (
+                    format!("{:04x}", symbol.value()),
+                    format!("{section} label"),
+                ),
+                (SymbolType::Label, None) => {
+                    (format!("{:04x}", symbol.value()), String::from("label"))
+                }
+                (SymbolType::Constant, _) => (symbol.value().to_string(), String::from("constant")),
+            };
+
+            writeln!(f, "{:<24} {:<8} {}", symbol.name(), value, kind)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assembler::Assembler;
+
+    #[test]
+    fn test_listing() {
+        let mut assembler = Assembler::new();
+        assembler.generate_listing = true;
+        assembler
+            .assemble(
+                "// Counts down\n\
+                 .equ START #300\n\
+                 main: LI $1 #70000\n\
+                 .loop: DEC $1\n\
+                 .data\n\
+                 name: .asciiz \"hi\"\n\
+                 .code\n\
+                 HLT",
+            )
+            .unwrap();
+
+        let listing = assembler.listing.unwrap();
+        assert_eq!(listing.lines.len(), 7);
+        assert_eq!(listing.lines[1].bytes.len(), 24);
+
+        assert_eq!(
+            listing.to_string(),
+            "Section Address Bytes        Line   Source\n\
+             code    0000                 2      .equ START #300\n\
+             code    0000    00 01 00 01  3      main: LI $1 #70000\n\
+             \x20       0004    00 1f 01 00\n\
+             \x20       0008    03 01 01 1f\n\
+             \x20       000c    03 01 01 1f\n\
+             \x20       0010    00 1f 11 70\n\
+             \x20       0014    01 01 01 1f\n\
+             code    0018    13 01 00 00  4      .loop: DEC $1\n\
+             data    0000                 5      .data\n\
+             data    0000    68 69 00     6      name: .asciiz \"hi\"\n\
+             code    001c                 7      .code\n\
+             code    001c    05 00 00 00  8      HLT\n\
+             \n\
+             Symbol                   Value    Kind\n\
+             main                     0000     code label\n\
+             main.loop                0018     code label\n\
+             name                     0000     data label\n\
+             START                    300      constant\n"
+        );
+    }
+}
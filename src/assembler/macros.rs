@@ -0,0 +1,198 @@
+use std::{collections::HashSet, sync::Arc};
+
+use super::{error::ErrorKind, label::is_numeric, Expansion, Location, Token};
+
+/// How deep macro invocations can be nested inside other expansions, this is
+/// what stops a macro that (indirectly) invokes itself
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// How many tokens `.rept` blocks can expand to in one assembly, nested blocks
+/// included and an empty block counted as one token per copy, this is what
+/// stops a typo in a repeat count from hanging the assembler
+pub const MAX_REPEAT_TOKENS: usize = 1 << 20;
+
+/// A token together with where it came from, this is what the parser consumes
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceToken {
+    pub token: Token,
+    pub location: Location,
+    pub starts_line: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<SourceToken>,
+    pub location: Location,
+}
+
+impl Macro {
+    /// Returns the body with every parameter replaced by its argument. Labels
+    /// declared inside the body get `id` appended so that each expansion
+    /// defines its own copy of them, numeric labels are left alone since they
+    /// can be declared more than once anyway.
+    pub fn expand(
+        &self,
+        arguments: &[Vec<SourceToken>],
+        invocation: Location,
+        id: usize,
+    ) -> Result<Vec<SourceToken>, ErrorKind> {
+        if arguments.len() != self.parameters.len() {
+            return Err(ErrorKind::MacroArgumentCount {
+                name: self.name.clone(),
+                expected: self.parameters.len(),
+                found: arguments.len(),
+            });
+        }
+
+        let expansion = Arc::new(Expansion {
+            macro_name: self.name.clone(),
+            invocation,
+        });
+        let local_labels: HashSet<&str> = self
+            .body
+            .iter()
+            .filter_map(|t| match &t.token {
+                Token::LabelDeclaration { value } if !is_numeric(value) => Some(value.as_str()),
+                _ => None,
+            })
+            .collect();
+        let local_name = |name: &str| format!("{name}#{id}");
+
+        let mut expanded = vec![];
+        for t in &self.body {
+            let location = Location {
+                expanded_from: Some(expansion.clone()),
+                ..t.location.clone()
+            };
+
+            let token = match &t.token {
+                Token::Identifier { name } => {
+                    if let Some(index) = self.parameters.iter().position(|p| p == name) {
+                        for (i, argument) in arguments[index].iter().enumerate() {
+                            expanded.push(SourceToken {
+                                starts_line: t.starts_line && i == 0,
+                                ..argument.clone()
+                            });
+                        }
+                        continue;
+                    }
+
+                    t.token.clone()
+                }
+                Token::LabelDeclaration { value } if local_labels.contains(value.as_str()) => {
+                    Token::LabelDeclaration {
+                        value: local_name(value),
+                    }
+                }
+                Token::Label { name } if local_labels.contains(name.as_str()) => Token::Label {
+                    name: local_name(name),
+                },
+                token => token.clone(),
+            };
+
+            expanded.push(SourceToken {
+                token,
+                location,
+                starts_line: t.starts_line,
+            });
+        }
+
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+
+    fn source_token(token: Token, line: usize, column: usize, starts_line: bool) -> SourceToken {
+        SourceToken {
+            token,
+            location: Location::new(line, column),
+            starts_line,
+        }
+    }
+
+    fn inc_macro() -> Macro {
+        Macro {
+            name: String::from("bump"),
+            parameters: vec![String::from("reg")],
+            body: vec![
+                source_token(
+                    Token::LabelDeclaration {
+                        value: String::from("again"),
+                    },
+                    2,
+                    1,
+                    true,
+                ),
+                source_token(Token::Op { code: Opcode::INC }, 2, 8, false),
+                source_token(
+                    Token::Identifier {
+                        name: String::from("reg"),
+                    },
+                    2,
+                    12,
+                    false,
+                ),
+                source_token(Token::Op { code: Opcode::LOAD }, 3, 1, true),
+                source_token(Token::Register { register: 31 }, 3, 6, false),
+                source_token(
+                    Token::Label {
+                        name: String::from("again"),
+                    },
+                    3,
+                    10,
+                    false,
+                ),
+            ],
+            location: Location::new(1, 1),
+        }
+    }
+
+    #[test]
+    fn test_expand_substitutes_arguments_and_renames_labels() {
+        let argument = vec![source_token(Token::Register { register: 4 }, 10, 6, false)];
+
+        let expanded = inc_macro()
+            .expand(&[argument], Location::new(10, 1), 7)
+            .unwrap();
+
+        let tokens: Vec<Token> = expanded.iter().map(|t| t.token.clone()).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LabelDeclaration {
+                    value: String::from("again#7")
+                },
+                Token::Op { code: Opcode::INC },
+                Token::Register { register: 4 },
+                Token::Op { code: Opcode::LOAD },
+                Token::Register { register: 31 },
+                Token::Label {
+                    name: String::from("again#7")
+                },
+            ]
+        );
+
+        // Body tokens point into the definition, arguments into the invocation
+        assert_eq!(expanded[1].location.line, 2);
+        assert_eq!(expanded[1].location.expansion_depth(), 1);
+        assert_eq!(expanded[2].location, Location::new(10, 6));
+    }
+
+    #[test]
+    fn test_expand_argument_count() {
+        assert_eq!(
+            inc_macro().expand(&[], Location::new(10, 1), 0),
+            Err(ErrorKind::MacroArgumentCount {
+                name: String::from("bump"),
+                expected: 1,
+                found: 0
+            })
+        );
+    }
+}
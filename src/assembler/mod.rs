@@ -1,23 +1,71 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::Path, sync::Arc};
 
 use crate::instruction::Opcode;
 
+use self::{error::LexError, expression::Expr, pseudo::PseudoOpcode};
+
+#[allow(clippy::module_inception)]
 pub mod assembler;
 mod assembler_instruction;
+mod conditional;
+pub mod error;
+mod expression;
+mod label;
 mod lexer;
+pub mod listing;
+mod macros;
 mod parser;
 pub mod program;
-mod symbol;
+pub mod pseudo;
+pub mod register;
+pub mod session;
+pub mod symbol;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    Op { code: Opcode },
-    Register { register: i32 },
-    IntOperand { operand: i32 },
-    StringOperand { operand: String },
-    LabelDeclaration { value: String },
-    Label { name: String },
-    Directive { value: String },
+    Op {
+        code: Opcode,
+    },
+    PseudoOp {
+        code: PseudoOpcode,
+    },
+    Register {
+        register: i32,
+    },
+    RegisterName {
+        name: String,
+    },
+    IntOperand {
+        operand: i32,
+    },
+    StringOperand {
+        operand: String,
+    },
+    LabelDeclaration {
+        value: String,
+    },
+    Label {
+        name: String,
+    },
+    Directive {
+        value: String,
+    },
+    Identifier {
+        name: String,
+    },
+    Operator {
+        operator: Operator,
+    },
+    Expression {
+        expr: Expr,
+    },
+    LeftParen,
+    RightParen,
+    Comma,
+    /// Text the lexer could not make a token of, the parser reports it
+    Invalid {
+        error: LexError,
+    },
     EOF,
 }
 
@@ -25,12 +73,21 @@ impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Token::Op { code } => write!(f, "{}", code),
+            Token::PseudoOp { code } => write!(f, "{}", code),
             Token::Register { register } => write!(f, "{}", register),
+            Token::RegisterName { name } => write!(f, "${}", name),
             Token::IntOperand { operand } => write!(f, "{}", operand),
             Token::StringOperand { operand } => write!(f, "{}", operand),
             Token::LabelDeclaration { value } => write!(f, "{}", value),
             Token::Label { name } => write!(f, "{}", name),
             Token::Directive { value } => write!(f, ".{}", value),
+            Token::Identifier { name } => write!(f, "{}", name),
+            Token::Operator { operator } => write!(f, "{}", operator),
+            Token::Expression { expr } => write!(f, "{}", expr),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+            Token::Invalid { error } => write!(f, "{}", error.text()),
             Token::EOF => write!(f, ""),
         }
     }
@@ -48,3 +105,101 @@ impl From<Token> for u8 {
         }
     }
 }
+
+/// Operators that can be used in constant expressions
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    ShiftLeft,
+    ShiftRight,
+    Ampersand,
+    Pipe,
+    Tilde,
+}
+
+impl Operator {
+    /// Binding power when used as a binary operator, higher binds tighter.
+    /// Returns `None` for operators that are only valid as unary operators.
+    pub fn binary_precedence(&self) -> Option<u8> {
+        match self {
+            Operator::Star | Operator::Slash | Operator::Percent => Some(5),
+            Operator::Plus | Operator::Minus => Some(4),
+            Operator::ShiftLeft | Operator::ShiftRight => Some(3),
+            Operator::Ampersand => Some(2),
+            Operator::Pipe => Some(1),
+            Operator::Tilde => None,
+        }
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operator::Plus => write!(f, "+"),
+            Operator::Minus => write!(f, "-"),
+            Operator::Star => write!(f, "*"),
+            Operator::Slash => write!(f, "/"),
+            Operator::Percent => write!(f, "%"),
+            Operator::ShiftLeft => write!(f, "<<"),
+            Operator::ShiftRight => write!(f, ">>"),
+            Operator::Ampersand => write!(f, "&"),
+            Operator::Pipe => write!(f, "|"),
+            Operator::Tilde => write!(f, "~"),
+        }
+    }
+}
+
+/// Position of a token in the source, both line and column start at 1.
+/// Tokens produced by a macro expansion point into the macro definition
+/// and remember where the macro was invoked.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Location {
+    /// File the token was read from, `None` when assembling a string
+    pub file: Option<Arc<Path>>,
+    pub line: usize,
+    pub column: usize,
+    pub expanded_from: Option<Arc<Expansion>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expansion {
+    pub macro_name: String,
+    pub invocation: Location,
+}
+
+impl Location {
+    pub fn new(line: usize, column: usize) -> Location {
+        Location {
+            file: None,
+            line,
+            column,
+            expanded_from: None,
+        }
+    }
+
+    /// Number of macro expansions this location is nested in
+    pub fn expansion_depth(&self) -> usize {
+        self.expansions().count()
+    }
+
+    /// Walks the macro expansions from the innermost to the outermost invocation
+    pub fn expansions(&self) -> impl Iterator<Item = &Expansion> {
+        std::iter::successors(self.expanded_from.as_deref(), |expansion| {
+            expansion.invocation.expanded_from.as_deref()
+        })
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
@@ -1,161 +1,997 @@
 #![allow(dead_code)]
 
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
 use super::{
-    assembler_instruction::{AssemblerInstruction, AssemblerToken},
+    assembler_instruction::{evaluate_operand, AssemblerInstruction, AssemblerToken},
+    conditional::ConditionalStack,
+    error::{AssemblerError, ErrorKind},
+    expression::Expr,
+    label::LabelScopes,
     lexer::Lexer,
-    Token,
+    macros::{Macro, SourceToken, MAX_EXPANSION_DEPTH, MAX_REPEAT_TOKENS},
+    pseudo::PseudoOpcode,
+    register::{abi_register, is_valid_register},
+    symbol::{Symbol, SymbolTable, SymbolType},
+    Location, Operator, Token,
 };
 
+/// Where the parser reads its tokens from, included files and macro expansions
+/// are pushed on top of the file they appear in
+enum TokenSource {
+    File {
+        lexer: Lexer,
+        path: Option<Arc<Path>>,
+        canonical: Option<PathBuf>,
+    },
+    Tokens(VecDeque<SourceToken>),
+}
+
 pub struct Parser {
-    lexer: Lexer,
+    sources: Vec<TokenSource>,
+    /// Directories searched for `.include` files not found next to the including file
+    pub include_paths: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    /// Register names defined with `.alias`
+    aliases: HashMap<String, i32>,
+    labels: LabelScopes,
+    expansion_count: usize,
+    /// Tokens `.rept` blocks expanded to so far, see `MAX_REPEAT_TOKENS`
+    repeated_tokens: usize,
+    conditionals: ConditionalStack,
+    /// Constants whose value is known while parsing, used by `.if` and `.rept`
+    constants: SymbolTable,
+    /// Every label and constant name seen so far, used by `.ifdef`
+    defined: HashSet<String>,
     label: Option<Token>,
     current: Token,
+    current_location: Location,
+    current_starts_line: bool,
     peek: Token,
+    peek_location: Location,
+    peek_starts_line: bool,
     pub program: Vec<AssemblerToken>,
+    /// Source location of every entry in `program`
+    pub locations: Vec<Location>,
+    /// Text of every file that was read, by the file locations refer to
+    pub files: HashMap<Option<Arc<Path>>, String>,
 }
 
 impl Parser {
     pub fn new(source_code: &str) -> Parser {
         let lexer = Lexer::new(source_code);
         Parser {
-            lexer,
+            sources: vec![TokenSource::File {
+                lexer,
+                path: None,
+                canonical: None,
+            }],
+            include_paths: vec![],
+            macros: HashMap::new(),
+            aliases: HashMap::new(),
+            labels: LabelScopes::new(),
+            expansion_count: 0,
+            repeated_tokens: 0,
+            conditionals: ConditionalStack::new(),
+            constants: SymbolTable::new(),
+            defined: HashSet::new(),
             label: None,
             current: Token::EOF,
+            current_location: Location::default(),
+            current_starts_line: true,
             peek: Token::EOF,
+            peek_location: Location::default(),
+            peek_starts_line: true,
             program: vec![],
+            locations: vec![],
+            files: HashMap::from([(None, String::from(source_code))]),
+        }
+    }
+
+    /// Creates a parser for the contents of the file at `path`, locations will
+    /// name the file and includes are resolved relative to it
+    pub fn with_file(source_code: &str, path: &Path) -> Parser {
+        let mut parser = Parser::new(source_code);
+        parser.sources = vec![TokenSource::File {
+            lexer: Lexer::new(source_code),
+            path: Some(Arc::from(path)),
+            canonical: std::fs::canonicalize(path).ok(),
+        }];
+        parser.files = HashMap::from([(Some(Arc::from(path)), String::from(source_code))]);
+
+        parser
+    }
+
+    /// Creates a parser that reads the named sources one after the other, as
+    /// if each was included after the one before it. Locations name the
+    /// source they are in and includes are resolved relative to it.
+    pub fn with_sources(sources: &[(Arc<Path>, String)]) -> Parser {
+        let mut parser = Parser::new("");
+        for (path, source_code) in sources.iter().rev() {
+            parser.sources.push(TokenSource::File {
+                lexer: Lexer::new(source_code),
+                path: Some(path.clone()),
+                canonical: std::fs::canonicalize(path).ok(),
+            });
+            parser.files.insert(Some(path.clone()), source_code.clone());
         }
+
+        parser
+    }
+
+    /// Defines a constant before parsing starts, like a command line define
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.defined.insert(String::from(name));
+        self.constants
+            .add_symbol(Symbol::new(String::from(name), value, SymbolType::Constant));
     }
 
-    pub fn parse(&mut self) {
+    pub fn parse(&mut self) -> Result<(), AssemblerError> {
         self.read();
         self.read();
 
-        while let Some(instruction) = self.next() {
+        while let Some((instruction, location)) = self.next()? {
             self.program.push(instruction);
+            self.locations.push(location);
+        }
+
+        match self.labels.unresolved() {
+            Some((kind, location)) => Err(AssemblerError::new(kind, location)),
+            None => Ok(()),
         }
     }
 
-    fn parse_instruction(&mut self) -> Option<AssemblerToken> {
+    fn parse_instruction(&mut self) -> Result<Option<AssemblerToken>, AssemblerError> {
         match &self.current.clone() {
-            Token::Op { code: _ } => Some(AssemblerToken::Instruction {
-                assembler_instruction: self.parse_opcode_instruction(),
-            }),
-            Token::Directive { value: _ } => Some(AssemblerToken::Instruction {
-                assembler_instruction: self.parse_directive_instruction(),
-            }),
+            Token::Op { code: _ } | Token::PseudoOp { code: _ } => {
+                Ok(Some(AssemblerToken::Instruction {
+                    assembler_instruction: self.parse_opcode_instruction()?,
+                }))
+            }
+            Token::Directive { value: _ } => Ok(Some(AssemblerToken::Instruction {
+                assembler_instruction: self.parse_directive_instruction()?,
+            })),
             Token::LabelDeclaration { value: v } => {
+                let label_name = self.labels.declare(v);
                 self.read();
 
+                // A label on its own refers to whatever comes after it, or
+                // to the end of the program when nothing follows
                 let token_type = match &self.current {
-                    Token::Op { code: _ } => self.parse_opcode_instruction(),
-                    Token::Directive { value: _ } => self.parse_directive_instruction(),
-                    Token::EOF => todo!(),
-                    _ => todo!(),
+                    Token::Op { code: _ } | Token::PseudoOp { code: _ } => {
+                        self.parse_opcode_instruction()?
+                    }
+                    Token::Directive { value } if !is_preprocessor_directive(value) => {
+                        self.parse_directive_instruction()?
+                    }
+                    _ => AssemblerInstruction::default(),
                 };
 
-                Some(AssemblerToken::LabelDeclaration {
-                    label_name: String::from(v),
+                Ok(Some(AssemblerToken::LabelDeclaration {
+                    label_name,
                     assembler_instruction: token_type,
-                })
+                }))
+            }
+            Token::EOF => Ok(None),
+            Token::Identifier { name } => {
+                Err(self.error(ErrorKind::UnknownInstruction { name: name.clone() }))
             }
-            Token::EOF => None,
-            _ => panic!("Unexpected Token, instruction has to start with Opcode, Directive or LabelDeclaration"),
+            found => Err(self.error(ErrorKind::UnexpectedToken {
+                found: found.clone(),
+            })),
         }
     }
 
-    fn parse_opcode_instruction(&mut self) -> AssemblerInstruction {
+    fn parse_opcode_instruction(&mut self) -> Result<AssemblerInstruction, AssemblerError> {
         let op = self.current.clone();
 
         // Eat the OP token
         self.read();
 
-        let label = match &self.current {
-            Token::Label { name: _ } => {
-                let label = self.current.clone();
+        let label = match self.current.clone() {
+            Token::Label { name }
+                if !self.current_starts_line && !self.peek_is_binary_operator() =>
+            {
+                let name = self.reference_label(&name)?;
                 self.read();
-                Some(label)
+                Some(Token::Label { name })
             }
             _ => None,
         };
 
-        AssemblerInstruction {
+        Ok(AssemblerInstruction {
             opcode: Some(op),
             directive: None,
             label,
-            operand_one: self.next_operand(),
-            operand_two: self.next_operand(),
-            operand_three: self.next_operand(),
-        }
+            operand_one: self.next_operand()?,
+            operand_two: self.next_operand()?,
+            operand_three: self.next_operand()?,
+        })
     }
 
-    fn parse_directive_instruction(&mut self) -> AssemblerInstruction {
+    fn parse_directive_instruction(&mut self) -> Result<AssemblerInstruction, AssemblerError> {
         let dir = self.current.clone();
         // eat the Directive token
         self.read();
 
-        let label = match self.peek {
-            Token::Label { name: _ } => {
-                let label = self.current.clone();
+        // The name defined by .equ is a plain identifier and not an expression
+        let operand_one = match (&dir, &self.current) {
+            (Token::Directive { value }, Token::Identifier { name: _ })
+                if value == "equ" && !self.current_starts_line =>
+            {
+                let name = self.current.clone();
                 self.read();
-                Some(label)
+                Some(name)
             }
-            _ => None,
+            _ => self.next_operand()?,
         };
 
-        AssemblerInstruction {
+        Ok(AssemblerInstruction {
             opcode: None,
             directive: Some(dir),
-            label,
-            operand_one: self.next_operand(),
-            operand_two: self.next_operand(),
-            operand_three: self.next_operand(),
-        }
+            label: None,
+            operand_one,
+            operand_two: self.next_operand()?,
+            operand_three: self.next_operand()?,
+        })
     }
 
-    fn next_operand(&mut self) -> Option<Token> {
+    fn next_operand(&mut self) -> Result<Option<Token>, AssemblerError> {
+        // Operands always live on the same line as their instruction
+        if self.current_starts_line {
+            return Ok(None);
+        }
+
         match self.current {
-            Token::Register { register: _ } => {
-                let current = self.current.clone();
+            Token::Register { register: _ } | Token::RegisterName { name: _ } => {
+                let register = self.resolve_register(&self.current)?;
                 self.read();
 
-                Some(current)
+                Ok(Some(Token::Register { register }))
             }
             Token::StringOperand { operand: _ } => {
                 let current = self.current.clone();
                 self.read();
 
-                Some(current)
+                Ok(Some(current))
             }
-            Token::IntOperand { operand: _ } => {
-                let current = self.current.clone();
+            Token::IntOperand { operand: _ }
+            | Token::Label { name: _ }
+            | Token::Identifier { name: _ }
+            | Token::LeftParen
+            | Token::Operator {
+                operator: Operator::Minus | Operator::Tilde,
+            } => {
+                let operand = match self.parse_expression(0)? {
+                    Expr::Number(n) => Token::IntOperand { operand: n as i32 },
+                    Expr::Label(name) => Token::Label { name },
+                    Expr::Symbol(name) => Token::Identifier { name },
+                    expr => Token::Expression { expr },
+                };
+
+                Ok(Some(operand))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Parses an expression using precedence climbing, only binary operators
+    /// binding at least as tight as `min_precedence` are consumed
+    fn parse_expression(&mut self, min_precedence: u8) -> Result<Expr, AssemblerError> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Token::Operator { operator } = self.current {
+            let precedence = match operator.binary_precedence() {
+                Some(precedence) if precedence >= min_precedence => precedence,
+                _ => break,
+            };
+
+            if self.current_starts_line {
+                break;
+            }
+
+            self.read();
+            let rhs = self.parse_expression(precedence + 1)?;
+            lhs = Expr::Binary {
+                operator,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, AssemblerError> {
+        if self.current_starts_line {
+            return Err(self.error(ErrorKind::UnexpectedToken {
+                found: self.current.clone(),
+            }));
+        }
+
+        match self.current.clone() {
+            Token::Operator {
+                operator: operator @ (Operator::Minus | Operator::Tilde),
+            } => {
                 self.read();
+                let operand = self.parse_unary()?;
 
-                Some(current)
+                Ok(Expr::Unary {
+                    operator,
+                    operand: Box::new(operand),
+                })
             }
-            _ => None,
+            Token::LeftParen => {
+                self.read();
+                let expr = self.parse_expression(0)?;
+
+                if self.current != Token::RightParen || self.current_starts_line {
+                    return Err(self.error(ErrorKind::UnexpectedToken {
+                        found: self.current.clone(),
+                    }));
+                }
+                self.read();
+
+                Ok(expr)
+            }
+            Token::IntOperand { operand } => {
+                self.read();
+                Ok(Expr::Number(operand as i64))
+            }
+            Token::Label { name } => {
+                let name = self.reference_label(&name)?;
+                self.read();
+                Ok(Expr::Label(name))
+            }
+            Token::Identifier { name } => {
+                self.read();
+                Ok(Expr::Symbol(name))
+            }
+            found => Err(self.error(ErrorKind::UnexpectedToken { found })),
+        }
+    }
+
+    fn reference_label(&mut self, name: &str) -> Result<String, AssemblerError> {
+        let location = self.current_location.clone();
+        self.labels
+            .reference(name, &location)
+            .map_err(|kind| AssemblerError::new(kind, location))
+    }
+
+    fn peek_is_binary_operator(&self) -> bool {
+        match &self.peek {
+            Token::Operator { operator } => {
+                !self.peek_starts_line && operator.binary_precedence().is_some()
+            }
+            _ => false,
         }
     }
 
-    fn next(&mut self) -> Option<AssemblerToken> {
-        if self.current == Token::EOF {
-            return None;
+    fn error(&self, kind: ErrorKind) -> AssemblerError {
+        AssemblerError::new(kind, self.current_location.clone())
+    }
+
+    fn next(&mut self) -> Result<Option<(AssemblerToken, Location)>, AssemblerError> {
+        loop {
+            if let Token::Directive { value } = &self.current {
+                if is_conditional_directive(value) {
+                    let directive = value.clone();
+                    self.conditional_directive(&directive)?;
+                    continue;
+                }
+            }
+
+            if self.current == Token::EOF {
+                return match self.conditionals.innermost() {
+                    Some(block) => Err(AssemblerError::new(
+                        ErrorKind::UnterminatedBlock {
+                            directive: block.directive.clone(),
+                        },
+                        block.location.clone(),
+                    )),
+                    None => Ok(None),
+                };
+            }
+
+            if !self.conditionals.is_active() {
+                self.read();
+                self.skip_rest_of_line();
+                continue;
+            }
+
+            match &self.current {
+                Token::Directive { value } if value == "macro" => self.define_macro()?,
+                Token::Directive { value } if value == "include" => self.include_file()?,
+                Token::Directive { value } if value == "rept" => self.repeat()?,
+                Token::Directive { value } if value == "alias" => self.define_alias()?,
+                Token::Identifier { name } if self.macros.contains_key(name) => {
+                    self.expand_macro()?
+                }
+                _ => break,
+            }
         }
 
         self.label = None;
-        self.parse_instruction()
+        let location = self.current_location.clone();
+        let instruction = self.parse_instruction()?;
+
+        if let Some(instruction) = &instruction {
+            self.record_definition(instruction);
+        }
+
+        Ok(instruction.map(|instruction| (instruction, location)))
+    }
+
+    /// Remembers labels and constants so `.if` and `.ifdef` can refer to them
+    fn record_definition(&mut self, instruction: &AssemblerToken) {
+        match instruction {
+            AssemblerToken::LabelDeclaration { label_name, .. } => {
+                self.defined.insert(label_name.clone());
+            }
+            AssemblerToken::Instruction {
+                assembler_instruction: i,
+            } if i.get_directive_name() == Some("equ") => {
+                if let (Some(Token::Identifier { name }), Some(value)) =
+                    (&i.operand_one, &i.operand_two)
+                {
+                    self.defined.insert(name.clone());
+
+                    // Constants that refer to labels only get a value once the
+                    // assembler has laid out the program
+                    if let Ok(value) = evaluate_operand(value, &self.constants) {
+                        self.constants.add_symbol(Symbol::new(
+                            name.clone(),
+                            value,
+                            SymbolType::Constant,
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles `.if`, `.ifdef`, `.ifndef`, `.elif`, `.else` and `.endif`. Conditions
+    /// inside a block that is not assembled are never evaluated.
+    fn conditional_directive(&mut self, directive: &str) -> Result<(), AssemblerError> {
+        let location = self.current_location.clone();
+        // Eat the directive
+        self.read();
+
+        let active = self.conditionals.is_active();
+        let result = match directive {
+            "if" => {
+                let condition = match active {
+                    true => Some(self.parse_constant()? != 0),
+                    false => None,
+                };
+                self.conditionals
+                    .push(directive, condition, location.clone());
+                Ok(())
+            }
+            "ifdef" | "ifndef" => {
+                let condition = match active {
+                    true => Some(self.parse_is_defined()? == (directive == "ifdef")),
+                    false => None,
+                };
+                self.conditionals
+                    .push(directive, condition, location.clone());
+                Ok(())
+            }
+            "elif" => {
+                let condition = match self.conditionals.elif_needs_condition() {
+                    true => Some(self.parse_constant()? != 0),
+                    false => None,
+                };
+                self.conditionals.elif(condition)
+            }
+            "else" => self.conditionals.else_branch(),
+            _ => self.conditionals.end(),
+        };
+        result.map_err(|kind| AssemblerError::new(kind, location))?;
+
+        // Conditions that were not evaluated are still on the line
+        self.skip_rest_of_line();
+
+        Ok(())
+    }
+
+    /// Parses an expression that has to have a value while parsing
+    fn parse_constant(&mut self) -> Result<i64, AssemblerError> {
+        let location = self.current_location.clone();
+        let expr = self.parse_expression(0)?;
+        self.expect_end_of_line()?;
+
+        expr.evaluate(&self.constants)
+            .map_err(|kind| AssemblerError::new(kind, location))
+    }
+
+    fn parse_is_defined(&mut self) -> Result<bool, AssemblerError> {
+        let name = match &self.current {
+            Token::Identifier { name } | Token::Label { name } if !self.current_starts_line => {
+                name.clone()
+            }
+            found => {
+                return Err(self.error(ErrorKind::UnexpectedToken {
+                    found: found.clone(),
+                }))
+            }
+        };
+        self.read();
+        self.expect_end_of_line()?;
+
+        Ok(self.defined.contains(&name))
+    }
+
+    /// Handles `.rept count` by expanding everything up to the matching `.endr`
+    /// `count` times, labels declared inside get a fresh name in every copy
+    fn repeat(&mut self) -> Result<(), AssemblerError> {
+        let location = self.current_location.clone();
+        // Eat the .rept directive
+        self.read();
+
+        let count = self.parse_constant()?;
+        let count = usize::try_from(count).map_err(|_| {
+            AssemblerError::new(ErrorKind::InvalidRepeatCount { count }, location.clone())
+        })?;
+
+        let body = self.collect_block("rept", "endr").ok_or_else(|| {
+            AssemblerError::new(
+                ErrorKind::UnterminatedBlock {
+                    directive: String::from("rept"),
+                },
+                location.clone(),
+            )
+        })?;
+
+        let size = count.saturating_mul(body.len().max(1));
+        if size > MAX_REPEAT_TOKENS - self.repeated_tokens {
+            return Err(AssemblerError::new(
+                ErrorKind::RepeatLimit {
+                    limit: MAX_REPEAT_TOKENS,
+                },
+                location,
+            ));
+        }
+        self.repeated_tokens += size;
+
+        let block = Macro {
+            name: String::from(".rept"),
+            parameters: vec![],
+            body,
+            location: location.clone(),
+        };
+
+        let mut tokens = VecDeque::new();
+        for _ in 0..count {
+            let expanded = block
+                .expand(&[], location.clone(), self.expansion_count)
+                .map_err(|kind| AssemblerError::new(kind, location.clone()))?;
+            self.expansion_count += 1;
+            tokens.extend(expanded);
+        }
+        self.push_tokens(tokens);
+
+        Ok(())
+    }
+
+    /// Collects tokens up to the `.close` directive matching an already read
+    /// `.open` directive, returns `None` if the input ends first
+    fn collect_block(&mut self, open: &str, close: &str) -> Option<Vec<SourceToken>> {
+        let mut body = vec![];
+        let mut nested = 0;
+
+        loop {
+            match &self.current {
+                Token::EOF => return None,
+                Token::Directive { value } if value == open => nested += 1,
+                Token::Directive { value } if value == close => {
+                    if nested == 0 {
+                        self.read();
+                        return Some(body);
+                    }
+                    nested -= 1;
+                }
+                _ => {}
+            }
+
+            body.push(self.take_current());
+        }
+    }
+
+    /// Parses `.macro name param, param` up to the matching `.endm` and stores the
+    /// body so later invocations can be expanded
+    fn define_macro(&mut self) -> Result<(), AssemblerError> {
+        let location = self.current_location.clone();
+        // Eat the .macro directive
+        self.read();
+
+        let name = match &self.current {
+            Token::Identifier { name } if !self.current_starts_line => name.clone(),
+            found => {
+                return Err(self.error(ErrorKind::UnexpectedToken {
+                    found: found.clone(),
+                }))
+            }
+        };
+        if PseudoOpcode::from_str(&name.to_lowercase()).is_ok() {
+            return Err(self.error(ErrorKind::ReservedName { name }));
+        }
+        self.read();
+
+        let mut parameters = vec![];
+        while !self.at_end_of_line() {
+            match &self.current {
+                Token::Identifier { name } => parameters.push(name.clone()),
+                found => {
+                    return Err(self.error(ErrorKind::UnexpectedToken {
+                        found: found.clone(),
+                    }))
+                }
+            }
+            self.read();
+
+            if self.current == Token::Comma && !self.current_starts_line {
+                self.read();
+            } else if !self.at_end_of_line() {
+                return Err(self.error(ErrorKind::UnexpectedToken {
+                    found: self.current.clone(),
+                }));
+            }
+        }
+
+        let body = match self.collect_block("macro", "endm") {
+            Some(body) => body,
+            None => {
+                return Err(AssemblerError::new(
+                    ErrorKind::UnterminatedMacro { name },
+                    location,
+                ))
+            }
+        };
+
+        self.macros.insert(
+            name.clone(),
+            Macro {
+                name,
+                parameters,
+                body,
+                location,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Reads `.alias name $register`, the register can itself be a name
+    fn define_alias(&mut self) -> Result<(), AssemblerError> {
+        // Eat the .alias directive
+        self.read();
+
+        let location = self.current_location.clone();
+        let name = match &self.current {
+            Token::Identifier { name } if !self.current_starts_line => name.clone(),
+            found => {
+                return Err(self.error(ErrorKind::UnexpectedToken {
+                    found: found.clone(),
+                }))
+            }
+        };
+        self.read();
+
+        let register = match &self.current {
+            Token::Register { register: _ } | Token::RegisterName { name: _ }
+                if !self.current_starts_line =>
+            {
+                self.resolve_register(&self.current)?
+            }
+            found => {
+                return Err(self.error(ErrorKind::UnexpectedToken {
+                    found: found.clone(),
+                }))
+            }
+        };
+
+        if self.aliases.contains_key(&name) || abi_register(&name).is_some() {
+            return Err(AssemblerError::new(
+                ErrorKind::DuplicateSymbol { name },
+                location,
+            ));
+        }
+
+        self.read();
+        self.expect_end_of_line()?;
+        self.aliases.insert(name, register);
+
+        Ok(())
+    }
+
+    /// Turns a register token into a register number, names are looked up in
+    /// the aliases first and then in the calling convention
+    fn resolve_register(&self, t: &Token) -> Result<i32, AssemblerError> {
+        let register = match t {
+            Token::Register { register } => *register,
+            Token::RegisterName { name } => match self.aliases.get(name) {
+                Some(register) => *register,
+                None => abi_register(name)
+                    .ok_or_else(|| self.error(ErrorKind::UnknownRegister { name: name.clone() }))?,
+            },
+            found => {
+                return Err(self.error(ErrorKind::InvalidOperand {
+                    found: found.clone(),
+                }))
+            }
+        };
+
+        if is_valid_register(register) {
+            Ok(register)
+        } else {
+            Err(self.error(ErrorKind::RegisterOutOfRange { register }))
+        }
+    }
+
+    /// Replaces a macro invocation with its expansion, the expanded tokens are
+    /// read before anything that followed the invocation
+    fn expand_macro(&mut self) -> Result<(), AssemblerError> {
+        let invocation = self.current_location.clone();
+        let name = self.current.to_string();
+
+        if invocation.expansion_depth() >= MAX_EXPANSION_DEPTH {
+            return Err(AssemblerError::new(
+                ErrorKind::MacroRecursionLimit {
+                    name,
+                    limit: MAX_EXPANSION_DEPTH,
+                },
+                invocation,
+            ));
+        }
+        // Eat the macro name
+        self.read();
+
+        let mut arguments = vec![];
+        let mut argument = vec![];
+        let mut parens = 0;
+        while !self.at_end_of_line() {
+            match self.current {
+                Token::Comma if parens == 0 => {
+                    if argument.is_empty() {
+                        return Err(self.error(ErrorKind::UnexpectedToken {
+                            found: Token::Comma,
+                        }));
+                    }
+                    arguments.push(std::mem::take(&mut argument));
+                    self.read();
+
+                    if self.at_end_of_line() {
+                        return Err(self.error(ErrorKind::UnexpectedToken {
+                            found: self.current.clone(),
+                        }));
+                    }
+                    continue;
+                }
+                Token::LeftParen => parens += 1,
+                Token::RightParen if parens > 0 => parens -= 1,
+                _ => {}
+            }
+
+            argument.push(self.take_current());
+        }
+        if !argument.is_empty() {
+            arguments.push(argument);
+        }
+
+        let expanded = self.macros[&name]
+            .expand(&arguments, invocation.clone(), self.expansion_count)
+            .map_err(|kind| AssemblerError::new(kind, invocation))?;
+        self.expansion_count += 1;
+
+        self.push_tokens(VecDeque::from(expanded));
+
+        Ok(())
+    }
+
+    /// Makes `tokens` the next tokens to be read
+    fn push_tokens(&mut self, mut tokens: VecDeque<SourceToken>) {
+        // The current and peek tokens have already been read from the source
+        // below, they have to come after the new tokens
+        tokens.push_back(self.current_source_token());
+        tokens.push_back(SourceToken {
+            token: self.peek.clone(),
+            location: self.peek_location.clone(),
+            starts_line: self.peek_starts_line,
+        });
+        self.sources.push(TokenSource::Tokens(tokens));
+
+        self.read();
+        self.read();
+    }
+
+    /// Handles `.include "path"` by reading tokens from the included file until
+    /// it is exhausted, then parsing continues after the directive
+    fn include_file(&mut self) -> Result<(), AssemblerError> {
+        let location = self.current_location.clone();
+
+        // Only look at the path token, reading past it would pull in tokens
+        // that belong after the included file
+        let path = match &self.peek {
+            Token::StringOperand { operand } if !self.peek_starts_line => PathBuf::from(operand),
+            found => {
+                return Err(AssemblerError::new(
+                    ErrorKind::UnexpectedToken {
+                        found: found.clone(),
+                    },
+                    self.peek_location.clone(),
+                ))
+            }
+        };
+
+        let resolved = self.resolve_include(&path, &location).ok_or_else(|| {
+            AssemblerError::new(
+                ErrorKind::IncludeNotFound {
+                    path: path.display().to_string(),
+                },
+                location.clone(),
+            )
+        })?;
+        let canonical = std::fs::canonicalize(&resolved).ok();
+
+        let is_cycle = self.sources.iter().any(|source| match source {
+            TokenSource::File {
+                canonical: Some(open),
+                ..
+            } => Some(open) == canonical.as_ref(),
+            _ => false,
+        });
+        if is_cycle {
+            return Err(AssemblerError::new(
+                ErrorKind::IncludeCycle {
+                    path: resolved.display().to_string(),
+                },
+                location,
+            ));
+        }
+
+        let source = std::fs::read_to_string(&resolved).map_err(|e| {
+            AssemblerError::new(
+                ErrorKind::Io {
+                    path: resolved.display().to_string(),
+                    message: e.to_string(),
+                },
+                location.clone(),
+            )
+        })?;
+
+        let path: Arc<Path> = Arc::from(resolved.as_path());
+        self.sources.push(TokenSource::File {
+            lexer: Lexer::new(&source),
+            path: Some(path.clone()),
+            canonical,
+        });
+        self.files.insert(Some(path), source);
+
+        self.read();
+        self.read();
+
+        Ok(())
+    }
+
+    /// Looks for the file next to the file that includes it first and then in
+    /// every include path, in order
+    fn resolve_include(&self, path: &Path, location: &Location) -> Option<PathBuf> {
+        let relative = match location.file.as_deref().and_then(Path::parent) {
+            Some(directory) => directory.join(path),
+            None => path.to_path_buf(),
+        };
+
+        std::iter::once(relative)
+            .chain(
+                self.include_paths
+                    .iter()
+                    .map(|directory| directory.join(path)),
+            )
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn at_end_of_line(&self) -> bool {
+        self.current_starts_line || self.current == Token::EOF
+    }
+
+    fn expect_end_of_line(&self) -> Result<(), AssemblerError> {
+        if self.at_end_of_line() {
+            Ok(())
+        } else {
+            Err(self.error(ErrorKind::UnexpectedToken {
+                found: self.current.clone(),
+            }))
+        }
+    }
+
+    fn skip_rest_of_line(&mut self) {
+        while !self.at_end_of_line() {
+            self.read();
+        }
+    }
+
+    fn current_source_token(&self) -> SourceToken {
+        SourceToken {
+            token: self.current.clone(),
+            location: self.current_location.clone(),
+            starts_line: self.current_starts_line,
+        }
+    }
+
+    /// Reads the next token and returns the one that was current
+    fn take_current(&mut self) -> SourceToken {
+        let current = self.current_source_token();
+        self.read();
+
+        current
     }
 
     /// Reads and eats the next token
     pub fn read(&mut self) {
-        self.current = self.peek.clone();
-        self.peek = if let Some(token) = self.lexer.next() {
-            token
-        } else {
-            Token::EOF
+        let next = self.next_source_token();
+
+        self.current = std::mem::replace(&mut self.peek, next.token);
+        self.current_location = std::mem::replace(&mut self.peek_location, next.location);
+        self.current_starts_line = self.peek_starts_line;
+        self.peek_starts_line = next.starts_line;
+    }
+
+    /// Takes the next token from the innermost source, finished sources are
+    /// dropped except the outermost file which keeps returning EOF
+    fn next_source_token(&mut self) -> SourceToken {
+        let mut starts_line = false;
+
+        loop {
+            let is_outermost = self.sources.len() == 1;
+            let next = match self.sources.last_mut() {
+                Some(TokenSource::Tokens(tokens)) => tokens.pop_front(),
+                Some(TokenSource::File { lexer, path, .. }) => match lexer.lex() {
+                    Token::EOF if !is_outermost => {
+                        // Whatever follows an included file is on a new line
+                        starts_line = true;
+                        None
+                    }
+                    token => Some(SourceToken {
+                        token,
+                        location: Location {
+                            file: path.clone(),
+                            ..lexer.token_location()
+                        },
+                        starts_line: lexer.token_starts_line(),
+                    }),
+                },
+                None => unreachable!("the outermost source is never removed"),
+            };
+
+            match next {
+                Some(mut token) => {
+                    token.starts_line |= starts_line;
+                    return token;
+                }
+                None => {
+                    self.sources.pop();
+                }
+            }
         }
     }
 }
 
+fn is_conditional_directive(name: &str) -> bool {
+    matches!(name, "if" | "ifdef" | "ifndef" | "elif" | "else" | "endif")
+}
+
+/// Directives that are handled by the parser itself and never reach the assembler
+fn is_preprocessor_directive(name: &str) -> bool {
+    is_conditional_directive(name) || matches!(name, "macro" | "include" | "rept" | "alias")
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -164,7 +1000,7 @@ mod tests {
     #[test]
     fn test_parse_label() {
         let mut p = Parser::new("JMP @test");
-        p.parse();
+        p.parse().unwrap();
 
         assert_eq!(p.program.len(), 1);
 
@@ -190,7 +1026,7 @@ mod tests {
     #[test]
     fn test_parse_label_declaration() {
         let mut p = Parser::new("my_instruction: load $10 #10");
-        p.parse();
+        p.parse().unwrap();
 
         assert_eq!(p.program.len(), 1);
 
@@ -216,7 +1052,7 @@ mod tests {
     fn test_parse_instruction() {
         let mut p =
             Parser::new("my_string: .asciiz \"Hello world\"\nload $10 #10\nHLT\nADD $0 $10 $5");
-        p.parse();
+        p.parse().unwrap();
 
         assert_eq!(p.program.len(), 4);
 
@@ -287,4 +1123,155 @@ mod tests {
             }
         )
     }
+
+    #[test]
+    fn test_parse_expression_operand() {
+        let mut p = Parser::new("load $0 (@end - @start) * #2 + -SIZE\nhlt");
+        p.parse().unwrap();
+
+        assert_eq!(p.program.len(), 2);
+
+        let expected = Expr::Binary {
+            operator: Operator::Plus,
+            lhs: Box::new(Expr::Binary {
+                operator: Operator::Star,
+                lhs: Box::new(Expr::Binary {
+                    operator: Operator::Minus,
+                    lhs: Box::new(Expr::Label(String::from("end"))),
+                    rhs: Box::new(Expr::Label(String::from("start"))),
+                }),
+                rhs: Box::new(Expr::Number(2)),
+            }),
+            rhs: Box::new(Expr::Unary {
+                operator: Operator::Minus,
+                operand: Box::new(Expr::Symbol(String::from("SIZE"))),
+            }),
+        };
+
+        assert_eq!(
+            p.program[0],
+            AssemblerToken::Instruction {
+                assembler_instruction: AssemblerInstruction {
+                    opcode: Some(Token::Op {
+                        code: crate::instruction::Opcode::LOAD
+                    }),
+                    directive: None,
+                    label: None,
+                    operand_one: Some(Token::Register { register: 0 }),
+                    operand_two: Some(Token::Expression { expr: expected }),
+                    operand_three: None
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_operands_stop_at_end_of_line() {
+        let mut p = Parser::new("hlt\nstart:\ninc $0\nend:");
+        p.parse().unwrap();
+
+        assert_eq!(p.program.len(), 3);
+        assert_eq!(
+            p.program[1],
+            AssemblerToken::LabelDeclaration {
+                label_name: String::from("start"),
+                assembler_instruction: AssemblerInstruction {
+                    opcode: Some(Token::Op {
+                        code: crate::instruction::Opcode::INC
+                    }),
+                    directive: None,
+                    label: None,
+                    operand_one: Some(Token::Register { register: 0 }),
+                    operand_two: None,
+                    operand_three: None
+                }
+            }
+        );
+        assert_eq!(
+            p.program[2],
+            AssemblerToken::LabelDeclaration {
+                label_name: String::from("end"),
+                assembler_instruction: AssemblerInstruction::default(),
+            }
+        );
+        assert_eq!(p.locations[2], Location::new(4, 1));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut p = Parser::new("load $0 #1\nlaod $0 #1");
+        assert_eq!(
+            p.parse(),
+            Err(AssemblerError::new(
+                ErrorKind::UnknownInstruction {
+                    name: String::from("laod")
+                },
+                Location::new(2, 1)
+            ))
+        );
+
+        let mut p = Parser::new("load $0 (#1 + #2");
+        assert_eq!(
+            p.parse(),
+            Err(AssemblerError::new(
+                ErrorKind::UnexpectedToken { found: Token::EOF },
+                Location::new(1, 17)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_register_names() {
+        let mut p = Parser::new(".alias counter $t0\n.alias copy $counter\nadd $zero $copy $sp");
+        p.parse().unwrap();
+
+        assert_eq!(
+            p.program,
+            vec![AssemblerToken::Instruction {
+                assembler_instruction: AssemblerInstruction {
+                    opcode: Some(Token::Op {
+                        code: crate::instruction::Opcode::ADD
+                    }),
+                    operand_one: Some(Token::Register { register: 0 }),
+                    operand_two: Some(Token::Register { register: 7 }),
+                    operand_three: Some(Token::Register { register: 27 }),
+                    ..Default::default()
+                }
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_register_errors() {
+        let mut p = Parser::new("inc $40");
+        assert_eq!(
+            p.parse(),
+            Err(AssemblerError::new(
+                ErrorKind::RegisterOutOfRange { register: 40 },
+                Location::new(1, 5)
+            ))
+        );
+
+        let mut p = Parser::new("inc $a9");
+        assert_eq!(
+            p.parse(),
+            Err(AssemblerError::new(
+                ErrorKind::UnknownRegister {
+                    name: String::from("a9")
+                },
+                Location::new(1, 5)
+            ))
+        );
+
+        let mut p = Parser::new(".alias sp $1");
+        assert_eq!(
+            p.parse(),
+            Err(AssemblerError::new(
+                ErrorKind::DuplicateSymbol {
+                    name: String::from("sp")
+                },
+                Location::new(1, 8)
+            ))
+        );
+    }
 }
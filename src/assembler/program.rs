@@ -1,4 +1,4 @@
-use super::parser::Parser;
+use super::{assembler::Assembler, error::AssemblerError};
 
 pub struct Program;
 
@@ -7,21 +7,13 @@ impl Program {
         Program
     }
 
-    pub fn parse_program(source: &str) -> Vec<u8> {
-        let mut program: Vec<u8> = vec![];
-        let mut parser = Parser::new(source);
-        parser.parse();
-        let instructions = parser.program;
-
-        for instruction in &instructions {
-            match instruction {
-                super::assembler_instruction::AssemblerToken::Instruction {
-                    assembler_instruction: instruct,
-                } => program.append(&mut instruct.to_bytes()),
-                _ => {}
-            }
-        }
+    pub fn parse_program(source: &str) -> Result<Vec<u8>, AssemblerError> {
+        Assembler::new().assemble(source)
+    }
+}
 
-        program
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
     }
 }
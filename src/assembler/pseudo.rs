@@ -0,0 +1,316 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::instruction::Opcode;
+
+use super::{
+    assembler_instruction::{evaluate_operand, AssemblerInstruction},
+    error::ErrorKind,
+    symbol::SymbolTable,
+    Token,
+};
+
+/// Register the expansions use for intermediate values like jump targets, `$at`
+pub const SCRATCH_REGISTER: i32 = 31;
+/// Register `BLT` keeps the result of its comparison in, `$cc`
+pub const CONDITION_REGISTER: i32 = 30;
+
+/// Instructions that only exist in the assembler, each one is replaced by
+/// one or more real instructions
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PseudoOpcode {
+    /// `MOV $dst $src`
+    MOV,
+    /// `CLR $reg`
+    CLR,
+    NOP,
+    /// `B target`
+    B,
+    /// `BLT $a $b target`, branches when `$a < $b`
+    BLT,
+    /// `LI $reg value`, loads any 32-bit value
+    LI,
+}
+
+impl PseudoOpcode {
+    pub const ALL: [PseudoOpcode; 6] = [
+        PseudoOpcode::MOV,
+        PseudoOpcode::CLR,
+        PseudoOpcode::NOP,
+        PseudoOpcode::B,
+        PseudoOpcode::BLT,
+        PseudoOpcode::LI,
+    ];
+
+    /// Number of real instructions this expands to. This has to be known in
+    /// the first phase, so a value that can't be evaluated yet because it
+    /// refers to a later label is assumed to need the long form.
+    pub fn length(&self, instruction: &AssemblerInstruction, symbols: &SymbolTable) -> usize {
+        match self {
+            PseudoOpcode::MOV | PseudoOpcode::B => 2,
+            PseudoOpcode::CLR | PseudoOpcode::NOP => 1,
+            PseudoOpcode::BLT => 3,
+            PseudoOpcode::LI => match operands(instruction).get(1) {
+                Some(value) => match evaluate_operand(value, symbols) {
+                    Ok(value) if fits_immediate(value) => 1,
+                    _ => 6,
+                },
+                None => 1,
+            },
+        }
+    }
+
+    /// Replaces the pseudo-instruction with real instructions, `length` is
+    /// what [`PseudoOpcode::length`] returned for it in the first phase
+    pub fn expand(
+        &self,
+        instruction: &AssemblerInstruction,
+        symbols: &SymbolTable,
+        length: usize,
+    ) -> Result<Vec<AssemblerInstruction>, ErrorKind> {
+        let operands = operands(instruction);
+        let scratch = register(SCRATCH_REGISTER);
+
+        let expanded = match (self, operands.as_slice()) {
+            (PseudoOpcode::MOV, [dst, src]) => {
+                let (dst, src) = (expect_register(dst)?, expect_register(src)?);
+                vec![
+                    op(Opcode::LOAD, [scratch.clone(), int(0)]),
+                    op(Opcode::ADD, [dst, src, scratch]),
+                ]
+            }
+            (PseudoOpcode::CLR, [reg]) => vec![op(Opcode::LOAD, [expect_register(reg)?, int(0)])],
+            (PseudoOpcode::NOP, []) => vec![op(Opcode::LOAD, [scratch, int(0)])],
+            (PseudoOpcode::B, [target]) => vec![
+                op(Opcode::LOAD, [scratch.clone(), expect_value(target)?]),
+                op(Opcode::JMP, [scratch]),
+            ],
+            (PseudoOpcode::BLT, [a, b, target]) => {
+                let condition = register(CONDITION_REGISTER);
+                vec![
+                    op(
+                        Opcode::LT,
+                        [condition.clone(), expect_register(a)?, expect_register(b)?],
+                    ),
+                    op(Opcode::LOAD, [scratch.clone(), expect_value(target)?]),
+                    op(Opcode::JEQ, [scratch, condition]),
+                ]
+            }
+            (PseudoOpcode::LI, [reg, value]) => {
+                let reg = expect_register(reg)?;
+                let value = evaluate_operand(&expect_value(value)?, symbols)?;
+                if !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+                    return Err(ErrorKind::OperandOutOfRange { value, bits: 32 });
+                }
+
+                if length == 1 {
+                    vec![op(Opcode::LOAD, [reg, int(value)])]
+                } else {
+                    // Shift the upper half into place by multiplying with 2^16
+                    // in two steps, the VM wraps so negative values work too
+                    let bits = value as u32;
+                    vec![
+                        op(Opcode::LOAD, [reg.clone(), int((bits >> 16) as i64)]),
+                        op(Opcode::LOAD, [scratch.clone(), int(256)]),
+                        op(Opcode::MUL, [reg.clone(), reg.clone(), scratch.clone()]),
+                        op(Opcode::MUL, [reg.clone(), reg.clone(), scratch.clone()]),
+                        op(Opcode::LOAD, [scratch.clone(), int((bits & 0xFFFF) as i64)]),
+                        op(Opcode::ADD, [reg.clone(), reg, scratch]),
+                    ]
+                }
+            }
+            (_, operands) => {
+                return Err(match operands.get(self.operand_count()) {
+                    Some(extra) => ErrorKind::InvalidOperand {
+                        found: (*extra).clone(),
+                    },
+                    None => ErrorKind::UnexpectedToken { found: Token::EOF },
+                })
+            }
+        };
+
+        Ok(expanded)
+    }
+
+    fn operand_count(&self) -> usize {
+        match self {
+            PseudoOpcode::NOP => 0,
+            PseudoOpcode::CLR | PseudoOpcode::B => 1,
+            PseudoOpcode::MOV | PseudoOpcode::LI => 2,
+            PseudoOpcode::BLT => 3,
+        }
+    }
+}
+
+/// The operands in the order they were written, a label written right after
+/// the instruction name ends up in the label field
+fn operands(instruction: &AssemblerInstruction) -> Vec<&Token> {
+    instruction
+        .label
+        .iter()
+        .chain(instruction.operands())
+        .collect()
+}
+
+fn fits_immediate(value: i64) -> bool {
+    u16::try_from(value).is_ok()
+}
+
+fn expect_register(t: &Token) -> Result<Token, ErrorKind> {
+    match t {
+        Token::Register { register }
+            if *register == SCRATCH_REGISTER || *register == CONDITION_REGISTER =>
+        {
+            Err(ErrorKind::ReservedRegister {
+                register: *register,
+            })
+        }
+        Token::Register { register: _ } => Ok(t.clone()),
+        found => Err(ErrorKind::InvalidOperand {
+            found: found.clone(),
+        }),
+    }
+}
+
+fn expect_value(t: &Token) -> Result<Token, ErrorKind> {
+    match t {
+        Token::Register { register: _ } | Token::StringOperand { operand: _ } => {
+            Err(ErrorKind::InvalidOperand { found: t.clone() })
+        }
+        _ => Ok(t.clone()),
+    }
+}
+
+fn op<const N: usize>(code: Opcode, operands: [Token; N]) -> AssemblerInstruction {
+    let mut operands = operands.into_iter();
+
+    AssemblerInstruction {
+        opcode: Some(Token::Op { code }),
+        operand_one: operands.next(),
+        operand_two: operands.next(),
+        operand_three: operands.next(),
+        ..Default::default()
+    }
+}
+
+fn register(register: i32) -> Token {
+    Token::Register { register }
+}
+
+fn int(value: i64) -> Token {
+    Token::IntOperand {
+        operand: value as i32,
+    }
+}
+
+impl FromStr for PseudoOpcode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mov" => Ok(PseudoOpcode::MOV),
+            "clr" => Ok(PseudoOpcode::CLR),
+            "nop" => Ok(PseudoOpcode::NOP),
+            "b" => Ok(PseudoOpcode::B),
+            "blt" => Ok(PseudoOpcode::BLT),
+            "li" => Ok(PseudoOpcode::LI),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for PseudoOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(code: PseudoOpcode, operands: Vec<Token>) -> AssemblerInstruction {
+        let mut operands = operands.into_iter();
+
+        AssemblerInstruction {
+            opcode: Some(Token::PseudoOp { code }),
+            operand_one: operands.next(),
+            operand_two: operands.next(),
+            operand_three: operands.next(),
+            ..Default::default()
+        }
+    }
+
+    fn encode(instructions: Vec<AssemblerInstruction>) -> Vec<u8> {
+        instructions
+            .iter()
+            .flat_map(|i| i.to_bytes(&SymbolTable::new()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_mov_and_blt() {
+        let symbols = SymbolTable::new();
+
+        let mov = instruction(PseudoOpcode::MOV, vec![register(1), register(2)]);
+        assert_eq!(
+            encode(PseudoOpcode::MOV.expand(&mov, &symbols, 2).unwrap()),
+            vec![0, 31, 0, 0, 1, 1, 2, 31]
+        );
+
+        let blt = instruction(PseudoOpcode::BLT, vec![register(1), register(2), int(12)]);
+        assert_eq!(
+            encode(PseudoOpcode::BLT.expand(&blt, &symbols, 3).unwrap()),
+            vec![12, 30, 1, 2, 0, 31, 0, 12, 15, 31, 30, 0]
+        );
+    }
+
+    #[test]
+    fn test_li_length() {
+        let symbols = SymbolTable::new();
+
+        let small = instruction(PseudoOpcode::LI, vec![register(1), int(0xFFFF)]);
+        assert_eq!(PseudoOpcode::LI.length(&small, &symbols), 1);
+
+        let big = instruction(PseudoOpcode::LI, vec![register(1), int(0x10000)]);
+        assert_eq!(PseudoOpcode::LI.length(&big, &symbols), 6);
+        assert_eq!(
+            encode(PseudoOpcode::LI.expand(&big, &symbols, 6).unwrap()),
+            vec![0, 1, 0, 1, 0, 31, 1, 0, 3, 1, 1, 31, 3, 1, 1, 31, 0, 31, 0, 0, 1, 1, 1, 31]
+        );
+
+        let forward = instruction(
+            PseudoOpcode::LI,
+            vec![
+                register(1),
+                Token::Label {
+                    name: String::from("end"),
+                },
+            ],
+        );
+        assert_eq!(PseudoOpcode::LI.length(&forward, &symbols), 6);
+    }
+
+    #[test]
+    fn test_invalid_operands() {
+        let symbols = SymbolTable::new();
+
+        let scratch = instruction(PseudoOpcode::CLR, vec![register(31)]);
+        assert_eq!(
+            PseudoOpcode::CLR.expand(&scratch, &symbols, 1),
+            Err(ErrorKind::ReservedRegister { register: 31 })
+        );
+
+        let missing = instruction(PseudoOpcode::MOV, vec![register(1)]);
+        assert_eq!(
+            PseudoOpcode::MOV.expand(&missing, &symbols, 2),
+            Err(ErrorKind::UnexpectedToken { found: Token::EOF })
+        );
+
+        let extra = instruction(PseudoOpcode::NOP, vec![register(1)]);
+        assert_eq!(
+            PseudoOpcode::NOP.expand(&extra, &symbols, 1),
+            Err(ErrorKind::InvalidOperand { found: register(1) })
+        );
+    }
+}
@@ -0,0 +1,35 @@
+pub const REGISTER_COUNT: i32 = crate::vm::REGISTER_COUNT as i32;
+
+/// Names of the registers in the calling convention, indexed by register number
+pub const ABI_NAMES: [&str; REGISTER_COUNT as usize] = [
+    "zero", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "t8", "t9", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "sp", "fp", "ra", "cc",
+    "at",
+];
+
+/// Looks up the register number of a calling convention name like `sp` or `a0`
+pub fn abi_register(name: &str) -> Option<i32> {
+    ABI_NAMES
+        .iter()
+        .position(|abi_name| *abi_name == name)
+        .map(|register| register as i32)
+}
+
+pub fn is_valid_register(register: i32) -> bool {
+    (0..REGISTER_COUNT).contains(&register)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abi_register() {
+        assert_eq!(abi_register("zero"), Some(0));
+        assert_eq!(abi_register("a0"), Some(3));
+        assert_eq!(abi_register("t9"), Some(16));
+        assert_eq!(abi_register("sp"), Some(27));
+        assert_eq!(abi_register("at"), Some(31));
+        assert_eq!(abi_register("t10"), None);
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::debug_info::DebugInfo;
+
+use super::{assembler::Assembler, error::AssemblerError, symbol::SymbolTable};
+
+/// Assembles a program one input at a time, the way the REPL reads it.
+/// Every input is assembled together with the ones before it, so labels,
+/// constants, macros and data stay defined, and code that refers to a label
+/// that isn't defined yet is kept until it is. Locations name the input they
+/// are in, `<input 3>` for the third one, so diagnostics point at the line
+/// within it.
+#[derive(Debug, Default, Clone)]
+pub struct Session {
+    /// Directories searched by `.include`
+    pub include_paths: Vec<PathBuf>,
+    /// Every input that assembled with its name, one after the other
+    inputs: Vec<(Arc<Path>, String)>,
+    assembler: Assembler,
+    program: Vec<u8>,
+    /// Start of the code that hasn't been run yet
+    pending: usize,
+}
+
+/// What feeding an input to a session changed
+#[derive(Debug, PartialEq, Clone)]
+pub struct Update {
+    /// Code that is ready to run, `None` when there is none or when it
+    /// refers to labels that aren't defined yet
+    pub run: Option<Range<usize>>,
+    /// Labels the program refers to that aren't defined yet
+    pub undefined: Vec<String>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    /// Adds `input` to the program. When it doesn't assemble the session is
+    /// left as it was.
+    pub fn feed(&mut self, input: &str) -> Result<Update, AssemblerError> {
+        let name = format!("<input {}>", self.inputs.len() + 1);
+        self.add(Arc::from(Path::new(&name)), input)
+    }
+
+    /// Adds `source`, the contents of the file at `path`, to the program like
+    /// `feed`, but locations name the file and its includes are resolved
+    /// relative to it
+    pub fn feed_file(&mut self, path: &Path, source: &str) -> Result<Update, AssemblerError> {
+        self.add(Arc::from(path), source)
+    }
+
+    fn add(&mut self, name: Arc<Path>, input: &str) -> Result<Update, AssemblerError> {
+        let mut inputs = self.inputs.clone();
+        inputs.push((name, String::from(input.trim_end_matches(['\r', '\n']))));
+
+        // Code only ever gets added at the end, what came before keeps its
+        // layout and only changes where it refers to a label defined now
+        let mut assembler = Assembler::new();
+        assembler.include_paths = self.include_paths.clone();
+        assembler.allow_undefined = true;
+        assembler.generate_debug_info = true;
+        let program = assembler.assemble_sources(&inputs)?;
+
+        let mut undefined = std::mem::take(&mut assembler.undefined);
+        undefined.sort();
+
+        self.inputs = inputs;
+        self.assembler = assembler;
+        self.program = program;
+
+        let run = match undefined.is_empty() && self.pending < self.program.len() {
+            true => Some(self.pending..self.program.len()),
+            false => None,
+        };
+        if run.is_some() {
+            self.pending = self.program.len();
+        }
+
+        Ok(Update { run, undefined })
+    }
+
+    /// The program assembled from every input so far
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Every input so far, one after the other
+    pub fn source(&self) -> String {
+        self.inputs
+            .iter()
+            .map(|(_, input)| input.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.assembler.symbols
+    }
+
+    pub fn read_only_data(&self) -> &[u8] {
+        &self.assembler.read_only_data
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.assembler.debug_info.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::error::ErrorKind;
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new();
+
+        let update = session.feed(".equ TEN #10\n").unwrap();
+        assert_eq!(update.run, None);
+
+        let update = session.feed("start: LOAD $1 TEN").unwrap();
+        assert_eq!(update.run, Some(0..4));
+        assert_eq!(session.symbols().value("start"), Some(0));
+
+        // Runs once the label it jumps to is defined, together with
+        // everything entered in between
+        let update = session.feed("B @end").unwrap();
+        assert_eq!(update.run, None);
+        assert_eq!(update.undefined, vec![String::from("end")]);
+        assert_eq!(&session.program()[4..8], &[0, 31, 0, 0]);
+
+        session.feed(".data\nname: .asciiz \"hi\"\n.code").unwrap();
+        let update = session.feed("end: INC $1").unwrap();
+        assert_eq!(update.run, Some(4..16));
+        assert!(update.undefined.is_empty());
+        assert_eq!(&session.program()[4..8], &[0, 31, 0, 12]);
+        assert_eq!(session.read_only_data(), b"hi\0");
+
+        let update = session.feed("HLT").unwrap();
+        assert_eq!(update.run, Some(16..20));
+    }
+
+    #[test]
+    fn test_session_errors() {
+        let mut session = Session::new();
+        session.feed("start: LOAD $1 #1").unwrap();
+
+        let error = session.feed("start: HLT").unwrap_err();
+        assert_eq!(
+            error.kind,
+            ErrorKind::DuplicateSymbol {
+                name: String::from("start")
+            }
+        );
+        assert_eq!(session.source(), "start: LOAD $1 #1");
+        assert_eq!(session.program().len(), 4);
+
+        // Locations are within the input that was just entered
+        let error = session.feed("HLT\nLAOD $0 #1").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "<input 2>:2:1: unknown instruction `LAOD`"
+        );
+
+        let update = session.feed("HLT").unwrap();
+        assert_eq!(update.run, Some(4..8));
+    }
+}
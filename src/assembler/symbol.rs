@@ -3,28 +3,41 @@ use std::collections::HashMap;
 #[derive(Debug, PartialEq, Clone)]
 pub struct Symbol {
     name: String,
-    instruction: Vec<u8>,
+    value: i64,
     symbol_type: SymbolType,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum SymbolType {
     Label,
+    Constant,
 }
 
 impl Symbol {
-    pub fn new(name: String, instruction: Vec<u8>, symbol_type: SymbolType) -> Symbol {
+    pub fn new(name: String, value: i64, symbol_type: SymbolType) -> Symbol {
         Symbol {
             name,
-            instruction,
+            value,
             symbol_type,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct SymbolTable {
-    pub symbols: HashMap<String, Vec<u8>>,
+    pub symbols: HashMap<String, Symbol>,
 }
 
 impl SymbolTable {
@@ -34,53 +47,58 @@ impl SymbolTable {
         }
     }
 
-    pub fn add_symbol(&mut self, s: Symbol) {
-        self.symbols.entry(s.name.clone()).or_insert(s.instruction);
+    /// Adds the symbol to the table, returns false if a symbol with the
+    /// same name already exists, in which case the existing one is kept
+    pub fn add_symbol(&mut self, s: Symbol) -> bool {
+        if self.symbols.contains_key(&s.name) {
+            return false;
+        }
+
+        self.symbols.insert(s.name.clone(), s);
+        true
     }
 
-    pub fn get_symbol(&mut self, key: String) -> Option<&Vec<u8>> {
-        let symbol = self.symbols.get(&key);
-        symbol
+    pub fn get_symbol(&self, key: &str) -> Option<&Symbol> {
+        self.symbols.get(key)
+    }
+
+    pub fn value(&self, key: &str) -> Option<i64> {
+        self.get_symbol(key).map(Symbol::value)
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::assembler::{
-        assembler_instruction::AssemblerInstruction,
-        symbol::{Symbol, SymbolTable, SymbolType},
-        Token,
-    };
+    use crate::assembler::symbol::{Symbol, SymbolTable, SymbolType};
 
     #[test]
     fn test_symbol_table() {
         let mut symbol_tabel = SymbolTable::new();
-        let instruction = AssemblerInstruction {
-            opcode: Some(Token::Op {
-                code: crate::instruction::Opcode::JMP,
-            }),
-            directive: None,
-            label: Some(Token::Label {
-                name: String::from("test_label"),
-            }),
-            operand_one: None,
-            operand_two: None,
-            operand_three: None,
-        };
-
-        let symbol = Symbol::new(
-            String::from("test_label"),
-            instruction.to_bytes(),
-            SymbolType::Label,
-        );
-        symbol_tabel.add_symbol(symbol.clone());
-
-        let instruction: Vec<u8> = vec![06, 00, 00, 00];
+
+        let symbol = Symbol::new(String::from("test_label"), 4, SymbolType::Label);
+        assert!(symbol_tabel.add_symbol(symbol.clone()));
 
         assert_eq!(symbol_tabel.symbols.len(), 1);
-        assert_eq!(
-            symbol_tabel.get_symbol(String::from("test_label")),
-            Some(&instruction)
-        );
+        assert_eq!(symbol_tabel.get_symbol("test_label"), Some(&symbol));
+        assert_eq!(symbol_tabel.value("test_label"), Some(4));
+    }
+
+    #[test]
+    fn test_duplicate_symbol() {
+        let mut symbol_tabel = SymbolTable::new();
+
+        let symbol = Symbol::new(String::from("size"), 16, SymbolType::Constant);
+        assert!(symbol_tabel.add_symbol(symbol));
+
+        let symbol = Symbol::new(String::from("size"), 32, SymbolType::Constant);
+        assert!(!symbol_tabel.add_symbol(symbol));
+
+        assert_eq!(symbol_tabel.value("size"), Some(16));
     }
 }
@@ -0,0 +1,186 @@
+use rustyline::{
+    completion::Completer,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::{ValidationContext, ValidationResult, Validator},
+    Context, Helper,
+};
+
+use lib::{
+    assembler::{pseudo::PseudoOpcode, register::ABI_NAMES},
+    instruction::Opcode,
+};
+
+/// Every command the REPL understands, for completion
+pub const COMMANDS: [&str; 26] = [
+    ":back",
+    ":break",
+    ":continue",
+    ":delete",
+    ":heap",
+    ":load",
+    ":loadbin",
+    ":next",
+    ":pc",
+    ":program",
+    ":quit",
+    ":rc",
+    ":registers",
+    ":reset",
+    ":save",
+    ":step",
+    ":symbols",
+    ":until",
+    ":unwatch",
+    ":watch",
+    ":b",
+    ":c",
+    ":n",
+    ":s",
+    ":u",
+    ":w",
+];
+
+/// Directives that start a block and the ones that end it
+const BLOCK_STARTS: [&str; 5] = [".macro", ".if", ".ifdef", ".ifndef", ".rept"];
+const BLOCK_ENDS: [&str; 3] = [".endm", ".endif", ".endr"];
+
+/// Completes and validates lines for the line editor
+#[derive(Default)]
+pub struct EditorHelper {
+    /// Labels of the session, kept up to date by the REPL
+    pub labels: Vec<String>,
+}
+
+/// Whether the input opens a block like `.macro` or `.if` that it doesn't
+/// close, in which case the REPL keeps reading lines into it
+pub fn is_incomplete(input: &str) -> bool {
+    if input.starts_with(':') {
+        return false;
+    }
+
+    let mut depth = 0;
+    for line in input.lines() {
+        let code = line.split("//").next().unwrap_or_default();
+        for word in code.split_whitespace() {
+            if BLOCK_STARTS.contains(&word) {
+                depth += 1;
+            } else if BLOCK_ENDS.contains(&word) {
+                depth -= 1;
+            }
+        }
+    }
+
+    depth > 0
+}
+
+impl EditorHelper {
+    fn candidates(&self, line: &str, word: &str) -> Vec<String> {
+        let before = line[..line.len() - word.len()].trim();
+        let is_first = before.is_empty();
+        // A mnemonic can also follow a label declaration
+        let is_mnemonic = is_first
+            || (before.ends_with(':') && !before.starts_with(':') && !before.contains(' '));
+        let starts_with =
+            |candidate: &String| candidate.to_lowercase().starts_with(&word.to_lowercase());
+
+        let candidates: Vec<String> = if word.starts_with(':') && is_first {
+            COMMANDS.iter().map(|command| command.to_string()).collect()
+        } else if word.starts_with('$') {
+            (0..ABI_NAMES.len())
+                .map(|register| format!("${register}"))
+                .chain(ABI_NAMES.iter().map(|name| format!("${name}")))
+                .collect()
+        } else if let Some(label) = word.strip_prefix('@') {
+            return self
+                .labels
+                .iter()
+                .filter(|name| name.starts_with(label))
+                .map(|name| format!("@{name}"))
+                .collect();
+        } else if is_mnemonic {
+            let lowercase = word.chars().next().is_some_and(char::is_lowercase);
+            Opcode::ALL
+                .iter()
+                .map(|opcode| opcode.to_string())
+                .chain(PseudoOpcode::ALL.iter().map(|opcode| opcode.to_string()))
+                .map(|name| match lowercase {
+                    true => name.to_lowercase(),
+                    false => name.to_uppercase(),
+                })
+                .collect()
+        } else {
+            self.labels.clone()
+        };
+
+        candidates.into_iter().filter(starts_with).collect()
+    }
+}
+
+impl Completer for EditorHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |space| space + 1);
+
+        Ok((start, self.candidates(line, &line[start..])))
+    }
+}
+
+impl Validator for EditorHelper {
+    fn validate(&self, ctx: &mut ValidationContext<'_>) -> rustyline::Result<ValidationResult> {
+        match is_incomplete(ctx.input()) {
+            true => Ok(ValidationResult::Incomplete),
+            false => Ok(ValidationResult::Valid(None)),
+        }
+    }
+}
+
+impl Hinter for EditorHelper {
+    type Hint = String;
+}
+
+impl Highlighter for EditorHelper {}
+
+impl Helper for EditorHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete(".macro twice reg"));
+        assert!(is_incomplete(".rept #2\n.if DEBUG\nINC $1\n.endif"));
+        assert!(!is_incomplete(".rept #2\nINC $1\n.endr"));
+        assert!(!is_incomplete("LOAD $1 #1 // .macro in a comment"));
+        assert!(!is_incomplete(":load .macro"));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let helper = EditorHelper {
+            labels: vec![String::from("loop"), String::from("load_data")],
+        };
+
+        assert_eq!(helper.candidates(":lo", ":lo"), vec![":load", ":loadbin"]);
+        assert_eq!(helper.candidates("LO", "LO"), vec!["LOAD"]);
+        assert_eq!(helper.candidates("l", "l"), vec!["load", "lt", "ltq", "li"]);
+        assert_eq!(helper.candidates("main: BL", "BL"), vec!["BLT"]);
+        assert_eq!(
+            helper.candidates("ADD $s", "$s"),
+            vec!["$s0", "$s1", "$s2", "$s3", "$s4", "$s5", "$s6", "$s7", "$s8", "$s9", "$sp"]
+        );
+        assert_eq!(
+            helper.candidates("B @lo", "@lo"),
+            vec!["@loop", "@load_data"]
+        );
+        assert_eq!(helper.candidates(":b lo", "lo"), vec!["loop", "load_data"]);
+    }
+}
@@ -0,0 +1,448 @@
+mod editor;
+mod repl;
+
+use std::{
+    fmt::Display,
+    io::{BufWriter, IsTerminal},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
+
+use lib::{
+    assembler::assembler::Assembler,
+    debug_info::DebugInfo,
+    disassembler::disassemble,
+    trace::{JsonLines, Pretty},
+    verifier::verify,
+    vm::{RunStatus, VM},
+};
+use repl::REPL;
+
+const USAGE: &str = "\
+usage: serus asm <file.sasm> [-o <file.sbc>] [-g] [--listing] [-I <dir>] [-D <name>[=<value>]]
+       serus run <file.sasm|file.sbc> [--verify] [--trace] [--trace-json <file>] [--max-steps <n>]
+                 [-I <dir>] [-D <name>[=<value>]]
+       serus disasm <file.sbc>
+       serus repl [--script <file>]
+
+options:
+  -o <file>            where `asm` writes the bytecode, defaults to the input with a .sbc extension
+  -g                   also write debug info to <output>.map, `run` picks it up to name faulting lines
+  --listing            print a listing of the assembled program
+  --verify             check the program with the verifier before running it
+  --trace              print every instruction and what it changed to stderr
+  --trace-json <file>  write every instruction and what it changed to <file> as JSON Lines
+  --max-steps <n>      stop with an error after executing <n> instructions
+  -I <dir>             search <dir> for `.include`d files
+  -D <name>=<value>    define a constant before assembling, the value defaults to 1
+  --script <file>      run the REPL lines and commands in <file> instead of reading stdin
+
+`run` exits with the value the program left in $v0, a value outside 0..=255 is an error.";
+
+/// Register whose value becomes the exit status of `serus run`
+const EXIT_REGISTER: usize = 1;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Asm { input: PathBuf },
+    Run { input: PathBuf },
+    Disasm { input: PathBuf },
+    Repl,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: Command,
+    output: Option<PathBuf>,
+    debug_info: bool,
+    listing: bool,
+    verify: bool,
+    trace: bool,
+    trace_json: Option<PathBuf>,
+    max_steps: Option<u64>,
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, i64)>,
+    script: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+enum CliError {
+    /// The command line itself is wrong, the usage is printed with it
+    Usage(String),
+    Failed(String),
+}
+
+impl Options {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, CliError> {
+        let mut args = args.into_iter();
+        let usage = |message: String| CliError::Usage(message);
+
+        let command = match args.next().as_deref() {
+            None | Some("repl") => Command::Repl,
+            Some(name @ ("asm" | "run" | "disasm")) => {
+                let input = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| usage(format!("`{name}` needs a file")))?;
+
+                match name {
+                    "asm" => Command::Asm { input },
+                    "run" => Command::Run { input },
+                    _ => Command::Disasm { input },
+                }
+            }
+            Some(other) => return Err(usage(format!("unknown command `{other}`"))),
+        };
+
+        let mut options = Options {
+            command,
+            output: None,
+            debug_info: false,
+            listing: false,
+            verify: false,
+            trace: false,
+            trace_json: None,
+            max_steps: None,
+            include_paths: vec![],
+            defines: vec![],
+            script: None,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| usage(format!("`{arg}` needs a value")))
+            };
+
+            match arg.as_str() {
+                "-o" => options.output = Some(PathBuf::from(value()?)),
+                "-g" => options.debug_info = true,
+                "--listing" => options.listing = true,
+                "--verify" => options.verify = true,
+                "--trace" => options.trace = true,
+                "--trace-json" => options.trace_json = Some(PathBuf::from(value()?)),
+                "--max-steps" => {
+                    let steps = value()?;
+                    let steps = steps
+                        .parse()
+                        .map_err(|_| usage(format!("`{steps}` is not a number of steps")))?;
+                    options.max_steps = Some(steps);
+                }
+                "-I" => options.include_paths.push(PathBuf::from(value()?)),
+                "-D" => options.defines.push(parse_define(&value()?)?),
+                "--script" => options.script = Some(PathBuf::from(value()?)),
+                _ => return Err(usage(format!("unknown option `{arg}`"))),
+            }
+        }
+
+        options.check()?;
+        Ok(options)
+    }
+
+    /// Rejects options that the command would ignore
+    fn check(&self) -> Result<(), CliError> {
+        let command = match self.command {
+            Command::Asm { .. } => "asm",
+            Command::Run { .. } => "run",
+            Command::Disasm { .. } => "disasm",
+            Command::Repl => "repl",
+        };
+        let used = [
+            ("-o", self.output.is_some(), "asm"),
+            ("-g", self.debug_info, "asm"),
+            ("--listing", self.listing, "asm"),
+            ("--verify", self.verify, "run"),
+            ("--trace", self.trace, "run"),
+            ("--trace-json", self.trace_json.is_some(), "run"),
+            ("--max-steps", self.max_steps.is_some(), "run"),
+            ("--script", self.script.is_some(), "repl"),
+        ];
+
+        for (option, is_used, allowed) in used {
+            if is_used && command != allowed {
+                return Err(CliError::Usage(format!(
+                    "`{option}` can only be used with `{allowed}`"
+                )));
+            }
+        }
+
+        if self.trace && self.trace_json.is_some() {
+            return Err(CliError::Usage(String::from(
+                "`--trace` and `--trace-json` can't be used together",
+            )));
+        }
+
+        let assembles = matches!(self.command, Command::Asm { .. } | Command::Run { .. });
+        if !assembles && (!self.include_paths.is_empty() || !self.defines.is_empty()) {
+            return Err(CliError::Usage(String::from(
+                "`-I` and `-D` can only be used with `asm` and `run`",
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn assembler(&self) -> Assembler {
+        let mut assembler = Assembler::new();
+        assembler.include_paths = self.include_paths.clone();
+        assembler.defines = self.defines.iter().cloned().collect();
+        assembler.generate_listing = self.listing;
+        assembler.generate_debug_info = true;
+
+        assembler
+    }
+}
+
+fn parse_define(define: &str) -> Result<(String, i64), CliError> {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    let value = value
+        .parse()
+        .map_err(|_| CliError::Usage(format!("`{value}` is not a value for `{name}`")))?;
+
+    Ok((String::from(name), value))
+}
+
+fn main() -> ExitCode {
+    let result = Options::parse(std::env::args().skip(1)).and_then(|options| execute(&options));
+
+    match result {
+        Ok(status) => status,
+        Err(CliError::Usage(message)) => {
+            eprintln!("serus: {message}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(CliError::Failed(message)) => {
+            eprintln!("serus: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn execute(options: &Options) -> Result<ExitCode, CliError> {
+    match &options.command {
+        Command::Asm { input } => {
+            let mut assembler = options.assembler();
+            let program = assembler.assemble_file(input).map_err(failed)?;
+            let output = match &options.output {
+                Some(output) => output.clone(),
+                None => input.with_extension("sbc"),
+            };
+
+            write(&output, program)?;
+            if options.debug_info {
+                let info = assembler.debug_info.unwrap_or_default();
+                write(&DebugInfo::sidecar_path(&output), info.to_string())?;
+            }
+            if let Some(listing) = assembler.listing {
+                print!("{listing}");
+            }
+        }
+        Command::Run { input } => {
+            let mut vm = VM::new();
+            if input
+                .extension()
+                .is_some_and(|extension| extension == "sasm")
+            {
+                let mut assembler = options.assembler();
+                vm.program = assembler.assemble_file(input).map_err(failed)?;
+                vm.debug_info = assembler.debug_info;
+            } else {
+                vm.program = read(input)?;
+                vm.debug_info = read_debug_info(input)?;
+            }
+
+            run(&mut vm, options)?;
+            return Ok(ExitCode::from(exit_status(vm.registers[EXIT_REGISTER])?));
+        }
+        Command::Disasm { input } => print!("{}", disassemble(&read(input)?)),
+        Command::Repl => {
+            let mut repl = REPL::new(VM::new());
+            match &options.script {
+                Some(script) => {
+                    let file = std::fs::File::open(script).map_err(|e| {
+                        CliError::Failed(format!("could not read `{}`: {e}", script.display()))
+                    })?;
+                    repl.run(std::io::BufReader::new(file), true)
+                }
+                None if std::io::stdin().is_terminal() => repl
+                    .run_interactive()
+                    .map_err(|e| CliError::Failed(format!("could not read a line: {e}")))?,
+                None => repl.run(std::io::stdin().lock(), false),
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Runs the program to the end, or until it executed `--max-steps`
+/// instructions
+fn run(vm: &mut VM, options: &Options) -> Result<(), CliError> {
+    if options.verify {
+        verify(&vm.program).map_err(failed)?;
+    }
+    if let Some(path) = &options.trace_json {
+        let file = std::fs::File::create(path)
+            .map_err(|e| CliError::Failed(format!("could not write `{}`: {e}", path.display())))?;
+        let sink = JsonLines::new(BufWriter::new(file));
+        vm.set_tracer(Some(Arc::new(Mutex::new(sink))));
+    } else if options.trace {
+        let sink = Pretty::new(std::io::stderr());
+        vm.set_tracer(Some(Arc::new(Mutex::new(sink))));
+    }
+
+    let max_steps = match options.max_steps {
+        Some(max_steps) => max_steps,
+        None => return vm.run_fast().map_err(failed),
+    };
+
+    match vm.run_with_budget(max_steps) {
+        Ok(RunStatus::OutOfFuel { .. }) => Err(CliError::Failed(format!(
+            "stopped after {max_steps} steps at pc {}",
+            vm.pc()
+        ))),
+        Ok(_) => Ok(()),
+        Err(fault) => Err(failed(fault)),
+    }
+}
+
+/// The exit status for the value a program left in `EXIT_REGISTER`, values
+/// that don't fit aren't truncated, a program leaving 256 shouldn't pass for
+/// one that succeeded
+fn exit_status(value: i32) -> Result<u8, CliError> {
+    u8::try_from(value).map_err(|_| {
+        CliError::Failed(format!(
+            "the program left {value} in $v0, exit statuses go from 0 to 255"
+        ))
+    })
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, CliError> {
+    std::fs::read(path)
+        .map_err(|e| CliError::Failed(format!("could not read `{}`: {e}", path.display())))
+}
+
+/// Reads the debug info written next to a program, if there is any
+fn read_debug_info(program: &Path) -> Result<Option<DebugInfo>, CliError> {
+    let path = DebugInfo::sidecar_path(program);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let text = String::from_utf8(read(&path)?)
+        .map_err(|_| CliError::Failed(format!("`{}` is not text", path.display())))?;
+    let info = text
+        .parse()
+        .map_err(|e| CliError::Failed(format!("`{}`: {e}", path.display())))?;
+
+    Ok(Some(info))
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), CliError> {
+    std::fs::write(path, contents)
+        .map_err(|e| CliError::Failed(format!("could not write `{}`: {e}", path.display())))
+}
+
+fn failed(error: impl Display) -> CliError {
+    CliError::Failed(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, CliError> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse("run main.sasm --trace --max-steps 100 -D DEBUG -D SIZE=-4").unwrap();
+        assert_eq!(
+            options.command,
+            Command::Run {
+                input: PathBuf::from("main.sasm")
+            }
+        );
+        assert!(options.trace);
+        assert_eq!(options.max_steps, Some(100));
+        assert_eq!(
+            options.defines,
+            vec![(String::from("DEBUG"), 1), (String::from("SIZE"), -4)]
+        );
+
+        let options = parse("asm main.sasm -o out.sbc -g --listing").unwrap();
+        assert_eq!(options.output, Some(PathBuf::from("out.sbc")));
+        assert!(options.debug_info && options.listing);
+
+        assert_eq!(parse("").unwrap().command, Command::Repl);
+        assert_eq!(
+            parse("repl --script bug.txt").unwrap().script,
+            Some(PathBuf::from("bug.txt"))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let usage = |message: &str| Err(CliError::Usage(String::from(message)));
+
+        assert_eq!(parse("build main.sasm"), usage("unknown command `build`"));
+        assert_eq!(parse("run"), usage("`run` needs a file"));
+        assert_eq!(
+            parse("run a.sbc --max-steps"),
+            usage("`--max-steps` needs a value")
+        );
+        assert_eq!(
+            parse("run a.sbc --max-steps ten"),
+            usage("`ten` is not a number of steps")
+        );
+        assert_eq!(
+            parse("disasm a.sbc --trace"),
+            usage("`--trace` can only be used with `run`")
+        );
+        assert_eq!(
+            parse("run a.sbc --trace --trace-json trace.jsonl"),
+            usage("`--trace` and `--trace-json` can't be used together")
+        );
+        assert_eq!(parse("asm a.sasm --fast"), usage("unknown option `--fast`"));
+    }
+
+    #[test]
+    fn test_verify() {
+        assert!(parse("run main.sbc --verify").unwrap().verify);
+        assert!(!parse("run main.sbc").unwrap().verify);
+        assert_eq!(
+            parse("disasm main.sbc --verify"),
+            Err(CliError::Usage(String::from(
+                "`--verify` can only be used with `run`"
+            )))
+        );
+
+        // A program that fails verification isn't run at all
+        let options = parse("run main.sbc --verify").unwrap();
+        let mut vm = VM::new();
+        vm.program = vec![0, 1, 0, 3, 18, 1, 0, 0];
+        assert_eq!(
+            run(&mut vm, &options),
+            Err(CliError::Failed(String::from(
+                "pc 4: the program doesn't end in HLT or a jump"
+            )))
+        );
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.pc(), 0);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(exit_status(0), Ok(0));
+        assert_eq!(exit_status(255), Ok(255));
+        assert_eq!(
+            exit_status(256),
+            Err(CliError::Failed(String::from(
+                "the program left 256 in $v0, exit statuses go from 0 to 255"
+            )))
+        );
+        assert!(exit_status(-1).is_err());
+    }
+}
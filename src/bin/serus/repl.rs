@@ -0,0 +1,664 @@
+use std::{io::BufRead, path::Path};
+
+use rustyline::{error::ReadlineError, history::DefaultHistory, Editor};
+
+use lib::{
+    assembler::{
+        error::AssemblerError,
+        listing::{Listing, ListingSymbol},
+        register::{abi_register, is_valid_register},
+        session::{Session, Update},
+        symbol::SymbolType,
+    },
+    debugger::{Condition, Debugger, StopReason, Watchpoint},
+    disassembler::{decode, disassemble, Line, Operand},
+    vm::VM,
+};
+
+use crate::editor::{is_incomplete, EditorHelper};
+
+/// File in the home directory that keeps the history between sessions
+const HISTORY_FILE: &str = ".serus_history";
+
+/// Bytes shown on one row of `:heap`
+const HEAP_ROW: usize = 16;
+
+/// Number of instructions `:back` can undo
+const HISTORY_SIZE: usize = 10_000;
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct REPL {
+    debugger: Debugger,
+    session: Session,
+    /// Set by `:quit`, ends the read loop so the history still gets saved
+    quit: bool,
+}
+
+type LineEditor = Editor<EditorHelper, DefaultHistory>;
+
+impl REPL {
+    pub fn new(mut vm: VM) -> Self {
+        vm.set_history_size(HISTORY_SIZE);
+
+        Self {
+            debugger: Debugger::new(vm),
+            session: Session::new(),
+            quit: false,
+        }
+    }
+
+    /// Reads lines from `input` until it ends. Scripts set `echo`, so their
+    /// output shows what each line did.
+    pub fn run(&mut self, mut input: impl BufRead, echo: bool) {
+        let mut buffer = String::new();
+
+        loop {
+            let mut line = String::new();
+            if let Err(e) = input.read_line(&mut line) {
+                eprintln!("Could not read input: {e}");
+                break;
+            }
+            if line.is_empty() {
+                break;
+            }
+            if echo {
+                println!("> {}", line.trim_end());
+            }
+
+            // Lines that open a block are collected until it is closed
+            buffer.push_str(&line);
+            if !is_incomplete(&buffer) {
+                self.run_line(&std::mem::take(&mut buffer));
+            }
+            if self.quit {
+                return;
+            }
+        }
+
+        if !buffer.is_empty() {
+            self.run_line(&buffer);
+        }
+    }
+
+    /// Reads lines from the terminal with editing, completion and a history
+    /// that is kept in the home directory
+    pub fn run_interactive(&mut self) -> rustyline::Result<()> {
+        let mut editor: LineEditor = Editor::new()?;
+        editor.set_helper(Some(EditorHelper::default()));
+
+        let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE));
+        self.interact(&mut editor, history.as_deref(), |editor, prompt| {
+            editor.readline(prompt)
+        })
+    }
+
+    /// The read loop of `run_interactive`, `read` asks the editor for a line
+    fn interact(
+        &mut self,
+        editor: &mut LineEditor,
+        history: Option<&Path>,
+        mut read: impl FnMut(&mut LineEditor, &str) -> rustyline::Result<String>,
+    ) -> rustyline::Result<()> {
+        if let Some(history) = history {
+            // There is no history before the first session
+            let _ = editor.load_history(history);
+        }
+
+        while !self.quit {
+            if let Some(helper) = editor.helper_mut() {
+                helper.labels = self.labels();
+            }
+
+            let prompt = format!("serus:{}> ", self.debugger.vm().pc());
+            match read(editor, &prompt) {
+                Ok(line) => {
+                    editor.add_history_entry(line.as_str())?;
+                    self.run_line(&line);
+                }
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if let Some(history) = history {
+            editor.save_history(history)?;
+        }
+
+        Ok(())
+    }
+
+    fn run_line(&mut self, line: &str) {
+        if line.starts_with(":") {
+            self.run_command(line)
+        } else {
+            self.run_input(line)
+        }
+    }
+
+    fn labels(&self) -> Vec<String> {
+        let mut labels: Vec<String> = self
+            .session
+            .symbols()
+            .symbols
+            .values()
+            .filter(|symbol| *symbol.symbol_type() == SymbolType::Label)
+            .map(|symbol| String::from(symbol.name()))
+            .collect();
+        labels.sort();
+
+        labels
+    }
+
+    fn run_input(&mut self, input: &str) {
+        if let Err(message) = self.enter(|session| session.feed(input)) {
+            eprintln!("{message}");
+        }
+    }
+
+    /// Assembles an input with `feed` together with everything entered
+    /// before it and runs the code that is ready. Returns the diagnostic when
+    /// the input is discarded.
+    fn enter(
+        &mut self,
+        feed: impl FnOnce(&mut Session) -> Result<Update, AssemblerError>,
+    ) -> Result<(), String> {
+        // A session that fails to assemble is left as it was, one whose code
+        // faults is put back by hand
+        let session = self.session.clone();
+        let update = feed(&mut self.session).map_err(|e| e.to_string())?;
+
+        let saved = self.debugger.vm().clone();
+        let vm = self.debugger.vm_mut();
+        vm.program = self.session.program().to_vec();
+        vm.debug_info = self.session.debug_info().cloned();
+
+        match update.run {
+            Some(code) => {
+                vm.set_pc(code.start);
+                match self.debugger.resume() {
+                    StopReason::End | StopReason::Halted => {}
+                    StopReason::Fault(fault) => {
+                        *self.debugger.vm_mut() = saved;
+                        self.session = session;
+                        return Err(format!("{fault}, the input was discarded"));
+                    }
+                    reason => self.report(reason),
+                }
+            }
+            None if !update.undefined.is_empty() => {
+                println!("Waiting for {} to be defined", update.undefined.join(", "))
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn run_command(&mut self, input: &str) {
+        let mut words = input.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let arguments: Vec<&str> = words.collect();
+
+        match (command, arguments.as_slice()) {
+            (":quit" | ":q", []) => self.quit(),
+            (":registers" | ":r", []) => self.show_registers(),
+            (":program" | ":p", []) => self.show_program(),
+            (":step" | ":s", []) => self.step("1"),
+            (":step" | ":s", [count]) => self.step(count),
+            (":continue" | ":c", []) => {
+                let reason = self.debugger.resume();
+                self.report(reason)
+            }
+            (":next" | ":n", []) => {
+                let reason = self.debugger.step_over();
+                self.report(reason)
+            }
+            (":until" | ":u", [address]) => self.run_until(address),
+            (":back", []) => self.step_back("1"),
+            (":back", [count]) => self.step_back(count),
+            (":rc", [register]) => self.reverse_to_write(register),
+            (":break" | ":b", []) => self.show_breakpoints(),
+            (":break" | ":b", [address]) => self.add_breakpoint(address),
+            (":delete" | ":d", []) => self.debugger.clear_breakpoints(),
+            (":delete" | ":d", [address]) => self.remove_breakpoint(address),
+            (":watch" | ":w", []) => self.show_watchpoints(),
+            (":watch" | ":w", [location]) => self.add_watchpoint(location, None),
+            (":watch" | ":w", [location, operator, value]) => {
+                self.add_watchpoint(location, Some((operator, value)))
+            }
+            (":unwatch", [id]) => self.remove_watchpoint(id),
+            (":pc", []) => self.show_pc(),
+            (":reset", []) => self.debugger.reset(),
+            (":heap", []) => self.show_heap("0", None),
+            (":heap", [address, length]) => self.show_heap(address, Some(length)),
+            (":symbols", []) => self.show_symbols(),
+            (":load", [path]) => {
+                if let Err(message) = self.load(Path::new(path)) {
+                    eprintln!("{message}");
+                }
+            }
+            (":loadbin", [path]) => self.load_binary(Path::new(path)),
+            (":save", [path]) => self.save(Path::new(path)),
+            _ => eprintln!("Unknown command `{}`", input.trim_end()),
+        }
+    }
+
+    /// Enters the file as one input, diagnostics name the file and the line
+    /// within it
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read `{}`: {e}", path.display()))?;
+
+        if let Some(directory) = path.parent() {
+            if !self.session.include_paths.iter().any(|p| p == directory) {
+                self.session.include_paths.push(directory.to_path_buf());
+            }
+        }
+        self.enter(|session| session.feed_file(path, &source))
+    }
+
+    /// Enters the bytecode as the assembly it decodes to, so `:save` can
+    /// still write the session out. Jumps in the file assume it starts at
+    /// address 0, so it belongs in an empty session.
+    fn load_binary(&mut self, path: &Path) {
+        let program = match std::fs::read(path) {
+            Ok(program) => program,
+            Err(e) => return eprintln!("Could not read `{}`: {e}", path.display()),
+        };
+
+        let source: Vec<String> = decode(&program)
+            .into_iter()
+            .map(|(_, line)| match line {
+                Line::Instruction { opcode, operands } => {
+                    // Label names could clash with the session's labels
+                    let operands = operands
+                        .into_iter()
+                        .map(|operand| match operand {
+                            Operand::Label(value) => Operand::Immediate(value),
+                            operand => operand,
+                        })
+                        .collect();
+                    Line::Instruction { opcode, operands }.to_string()
+                }
+                line => line.to_string(),
+            })
+            .collect();
+
+        self.run_input(&source.join("\n"));
+    }
+
+    /// Writes every line entered so far to a file `:load` can replay
+    fn save(&self, path: &Path) {
+        let mut source = self.session.source();
+        source.push('\n');
+
+        if let Err(e) = std::fs::write(path, source) {
+            eprintln!("Could not write `{}`: {e}", path.display());
+        }
+    }
+
+    fn quit(&mut self) {
+        println!("Good bye, happy coding! :D");
+        self.quit = true;
+    }
+
+    fn show_registers(&self) {
+        for (index, register) in self.debugger.vm().registers.iter().enumerate() {
+            println!("Register: {} -> {}", index, register)
+        }
+    }
+
+    fn show_program(&self) {
+        print!("{}", disassemble(&self.debugger.vm().program));
+    }
+
+    fn step(&mut self, count: &str) {
+        match count.parse() {
+            Ok(count) => {
+                let reason = self.debugger.step(count);
+                self.report(reason)
+            }
+            Err(_) => eprintln!("`{count}` is not a number of steps"),
+        }
+    }
+
+    fn report(&self, reason: StopReason) {
+        match reason {
+            StopReason::Step => self.show_pc(),
+            StopReason::Fault(fault) => eprintln!("{fault}"),
+            reason => {
+                println!("{reason}");
+                self.show_pc();
+            }
+        }
+    }
+
+    fn show_pc(&self) {
+        let pc = self.debugger.vm().pc();
+        let info = self.debugger.vm().debug_info.as_ref();
+
+        let mut line = format!("pc {pc}");
+        if let Some((label, offset)) = info.and_then(|info| info.label(pc)) {
+            line.push_str(&format!(" in {label}+{offset}"));
+        }
+        if let Some(location) = info.and_then(|info| info.location(pc)) {
+            line.push_str(&format!(" at {location}"));
+        }
+
+        println!("{line}");
+    }
+
+    fn show_breakpoints(&self) {
+        for pc in self.debugger.breakpoints() {
+            println!("Breakpoint at {pc}");
+        }
+    }
+
+    fn add_breakpoint(&mut self, address: &str) {
+        if let Some(pc) = self.address(address) {
+            if !self.debugger.add_breakpoint(pc) {
+                eprintln!("There already is a breakpoint at {pc}");
+            }
+        }
+    }
+
+    fn remove_breakpoint(&mut self, address: &str) {
+        if let Some(pc) = self.address(address) {
+            if !self.debugger.remove_breakpoint(pc) {
+                eprintln!("There is no breakpoint at {pc}");
+            }
+        }
+    }
+
+    fn run_until(&mut self, address: &str) {
+        if let Some(pc) = self.address(address) {
+            let reason = self.debugger.run_until(pc);
+            self.report(reason)
+        }
+    }
+
+    fn step_back(&mut self, count: &str) {
+        let count = match count.parse() {
+            Ok(count) => count,
+            Err(_) => return eprintln!("`{count}` is not a number of steps"),
+        };
+
+        let undone = self.debugger.vm_mut().step_back(count);
+        if undone < count {
+            println!("The history only went back {undone} instructions");
+        }
+        self.show_pc();
+    }
+
+    fn reverse_to_write(&mut self, name: &str) {
+        let register = match self.register(name) {
+            Some(register) => register,
+            None => return eprintln!("`{name}` is not a register"),
+        };
+
+        match self.debugger.vm_mut().reverse_to_write(register) {
+            true => self.show_pc(),
+            false => eprintln!("{name} wasn't written as far back as the history goes"),
+        }
+    }
+
+    /// Reads a register given as `$n` or `$name`
+    fn register(&self, name: &str) -> Option<usize> {
+        let name = name.strip_prefix('$')?;
+        let register = name.parse().ok().or_else(|| abi_register(name))?;
+
+        is_valid_register(register).then_some(register as usize)
+    }
+
+    fn show_watchpoints(&self) {
+        for (id, watchpoint) in self.debugger.watchpoints() {
+            println!("Watchpoint {id}: {watchpoint}");
+        }
+    }
+
+    /// Watches a register given as `$n` or `$name`, or the heap byte at an
+    /// address, optionally only stopping when the value written compares to
+    /// `value` the way `operator` says
+    fn add_watchpoint(&mut self, location: &str, condition: Option<(&str, &str)>) {
+        let condition = match condition {
+            None => Condition::Write,
+            Some((operator, value)) => {
+                let value = match value.parse() {
+                    Ok(value) => value,
+                    Err(_) => return eprintln!("`{value}` is not a number"),
+                };
+                match operator {
+                    "==" => Condition::Equal(value),
+                    "!=" => Condition::NotEqual(value),
+                    "<" => Condition::Less(value),
+                    ">" => Condition::Greater(value),
+                    _ => return eprintln!("`{operator}` is not one of ==, !=, < or >"),
+                }
+            }
+        };
+
+        let watchpoint = match location.starts_with('$') {
+            true => match self.register(location) {
+                Some(register) => Watchpoint::Register {
+                    register,
+                    condition,
+                },
+                None => return eprintln!("`{location}` is not a register"),
+            },
+            false => match self.number(location) {
+                Some(address) => Watchpoint::Heap { address, condition },
+                None => return,
+            },
+        };
+
+        let id = self.debugger.add_watchpoint(watchpoint);
+        println!("Watchpoint {id}: {watchpoint}");
+    }
+
+    fn remove_watchpoint(&mut self, id: &str) {
+        match id.parse() {
+            Ok(id) if self.debugger.remove_watchpoint(id) => {}
+            _ => eprintln!("There is no watchpoint `{id}`"),
+        }
+    }
+
+    fn show_heap(&self, address: &str, length: Option<&str>) {
+        let heap = self.debugger.vm().heap();
+        let start = match self.number(address) {
+            Some(start) => start,
+            None => return,
+        };
+        let end = match length.map(|length| self.number(length)) {
+            Some(Some(length)) => start.saturating_add(length),
+            Some(None) => return,
+            None => heap.len(),
+        };
+
+        if start > heap.len() || end > heap.len() {
+            return eprintln!("The heap is only {} bytes long", heap.len());
+        }
+
+        for (i, row) in heap[start..end].chunks(HEAP_ROW).enumerate() {
+            let bytes: Vec<String> = row.iter().map(|byte| format!("{byte:02x}")).collect();
+            println!("{:04x}  {}", start + i * HEAP_ROW, bytes.join(" "));
+        }
+    }
+
+    fn show_symbols(&self) {
+        let sections = self
+            .session
+            .debug_info()
+            .map(|info| {
+                info.labels
+                    .iter()
+                    .map(|label| (label.name.clone(), label.section))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for ListingSymbol { symbol, section } in
+            Listing::new(vec![], self.session.symbols(), &sections).symbols
+        {
+            match (symbol.symbol_type(), section) {
+                (SymbolType::Label, Some(section)) => println!(
+                    "{:<24} {:04x}     {section} label",
+                    symbol.name(),
+                    symbol.value()
+                ),
+                (SymbolType::Label, None) => {
+                    println!("{:<24} {:04x}     label", symbol.name(), symbol.value())
+                }
+                (SymbolType::Constant, _) => println!("{:<24} {}", symbol.name(), symbol.value()),
+            }
+        }
+    }
+
+    /// Reads an address given as a number or as the name of a label
+    fn address(&self, address: &str) -> Option<usize> {
+        let label = address.strip_prefix('@').unwrap_or(address);
+        let symbol = self.session.symbols().get_symbol(label);
+
+        match symbol {
+            Some(symbol) if *symbol.symbol_type() == SymbolType::Label => {
+                Some(symbol.value() as usize)
+            }
+            _ => self.number(address),
+        }
+    }
+
+    fn number(&self, number: &str) -> Option<usize> {
+        let parsed = match number.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => number.parse(),
+        };
+
+        match parsed {
+            Ok(number) => Some(number),
+            Err(_) => {
+                eprintln!("`{number}` is not an address");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failing_input_is_discarded() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_line("LOAD $1 #5\n");
+        repl.run_line("LOAD $0 !\n");
+        repl.run_line("LOAD $2 #9\nDIV $3 $1 $0\n");
+
+        assert_eq!(repl.session.source(), "LOAD $1 #5");
+        assert_eq!(repl.debugger.vm().program.len(), 4);
+        assert_eq!(&repl.debugger.vm().registers[..3], &[0, 5, 0]);
+
+        repl.run_line("LOAD $2 #9\n");
+        assert_eq!(repl.debugger.vm().registers[2], 9);
+
+        // Diagnostics point at the line within the input just entered
+        assert_eq!(
+            repl.enter(|session| session.feed("LAOD $0 #1")),
+            Err(String::from("<input 3>:1:1: unknown instruction `LAOD`"))
+        );
+        assert_eq!(
+            repl.enter(|session| session.feed("INC $2\nDIV $3 $1 $0")),
+            Err(String::from(
+                "fault at <input 3>:2: division by zero, the input was discarded"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_load_command() {
+        let directory = std::env::temp_dir().join(format!("serus_load_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let bad = directory.join("bad.sasm");
+        std::fs::write(&bad, "LOAD $1 #1\nLOAD $2 #2\nLAOD $3 #3\n").unwrap();
+        let good = directory.join("good.sasm");
+        std::fs::write(&good, "LOAD $1 #1\nLOAD $2 #2\n").unwrap();
+
+        let mut repl = REPL::new(VM::new());
+        repl.run_line("LOAD $4 #4\n");
+        repl.run_line("LOAD $5 #5\n");
+
+        // Diagnostics name the file and the line within it
+        assert_eq!(
+            repl.load(&bad),
+            Err(format!("{}:3:1: unknown instruction `LAOD`", bad.display()))
+        );
+        assert_eq!(repl.load(&good), Ok(()));
+        assert_eq!(&repl.debugger.vm().registers[1..6], &[1, 2, 0, 4, 5]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_quit_saves_history() {
+        let history = std::env::temp_dir().join(format!("serus_history_{}", std::process::id()));
+        let _ = std::fs::remove_file(&history);
+
+        let mut repl = REPL::new(VM::new());
+        let mut editor: LineEditor = Editor::new().unwrap();
+        let mut lines = vec!["LOAD $1 #1", ":q", "LOAD $2 #2"].into_iter();
+        repl.interact(&mut editor, Some(&history), |_, _| {
+            lines.next().map(String::from).ok_or(ReadlineError::Eof)
+        })
+        .unwrap();
+
+        // Nothing is read after `:q`
+        assert_eq!(&repl.debugger.vm().registers[1..3], &[1, 0]);
+        let saved = std::fs::read_to_string(&history).unwrap();
+        assert!(saved.contains("LOAD $1 #1\n:q"));
+        assert!(!saved.contains("LOAD $2 #2"));
+
+        std::fs::remove_file(&history).unwrap();
+    }
+
+    #[test]
+    fn test_reverse_commands() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_line("LOAD $1 #5\nLOAD $2 #1\nINC $2\nINC $2");
+        assert_eq!(repl.debugger.vm().pc(), 16);
+
+        repl.run_line(":back 2");
+        assert_eq!(repl.debugger.vm().registers[2], 1);
+        assert_eq!(repl.debugger.vm().pc(), 8);
+
+        repl.run_line(":rc $v0");
+        assert_eq!(repl.debugger.vm().registers[1], 0);
+        assert_eq!(repl.debugger.vm().pc(), 0);
+
+        repl.run_line(":c");
+        assert_eq!(&repl.debugger.vm().registers[1..3], &[5, 3]);
+    }
+
+    #[test]
+    fn test_watch_command() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_line(":watch $v0 > 2");
+        repl.run_line(":watch 4");
+        repl.run_line(":watch $40");
+        repl.run_line(":watch $1 >= 2");
+
+        let watchpoints: Vec<String> = repl
+            .debugger
+            .watchpoints()
+            .map(|(id, watchpoint)| format!("{id}: {watchpoint}"))
+            .collect();
+        assert_eq!(watchpoints, vec!["1: $1 > 2", "2: heap[4]"]);
+
+        repl.run_line("LOAD $1 #1\nINC $1\nINC $1\nINC $1");
+        assert_eq!(repl.debugger.vm().registers[1], 3);
+        assert_eq!(repl.debugger.vm().pc(), 12);
+
+        repl.run_line(":unwatch 1");
+        repl.run_line(":c");
+        assert_eq!(repl.debugger.vm().registers[1], 4);
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::assembler::assembler::Section;
+
+/// First line of a debug info file, followed by the format version
+const HEADER: &str = "serus-debug";
+const VERSION: u32 = 1;
+
+/// Extension added to the program's path for the debug info written next to it
+pub const SIDECAR_EXTENSION: &str = "map";
+
+/// Links the bytes of an assembled program back to the source they came from
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    /// Ranges of code ordered by address, one per statement that produced code
+    pub ranges: Vec<SourceRange>,
+    pub labels: Vec<DebugLabel>,
+    /// Consecutive runs of statements in the same section, in source order
+    pub sections: Vec<SectionRange>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceRange {
+    pub start: u32,
+    /// One past the last byte of the range
+    pub end: u32,
+    pub location: SourceLocation,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLocation {
+    /// `None` when the program was assembled from a string
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugLabel {
+    pub name: String,
+    pub section: Section,
+    pub address: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SectionRange {
+    pub section: Section,
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugInfoError {
+    /// Line of the debug info file that could not be read
+    pub line: usize,
+}
+
+impl DebugInfo {
+    /// The source of the statement that produced the byte at `pc`
+    pub fn location(&self, pc: usize) -> Option<&SourceLocation> {
+        let index = self
+            .ranges
+            .partition_point(|range| range.end as usize <= pc);
+
+        self.ranges
+            .get(index)
+            .filter(|range| range.start as usize <= pc)
+            .map(|range| &range.location)
+    }
+
+    /// The closest code label at or before `pc`, with the distance from it
+    pub fn label(&self, pc: usize) -> Option<(&str, u32)> {
+        self.labels
+            .iter()
+            .filter(|label| label.section == Section::Code && label.address as usize <= pc)
+            .max_by_key(|label| label.address)
+            .map(|label| (label.name.as_str(), pc as u32 - label.address))
+    }
+
+    /// Where the debug info of the program at `path` is written
+    pub fn sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+        let mut path = path.as_ref().as_os_str().to_owned();
+        path.push(".");
+        path.push(SIDECAR_EXTENSION);
+
+        PathBuf::from(path)
+    }
+}
+
+impl Display for DebugInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{HEADER} {VERSION}")?;
+
+        for section in &self.sections {
+            writeln!(
+                f,
+                "section {} {} {}",
+                section.section, section.start, section.end
+            )?;
+        }
+
+        for label in &self.labels {
+            writeln!(
+                f,
+                "label {} {} {}",
+                label.section, label.address, label.name
+            )?;
+        }
+
+        // The file goes last so it can contain spaces
+        for range in &self.ranges {
+            let location = &range.location;
+            write!(
+                f,
+                "range {} {} {} {}",
+                range.start, range.end, location.line, location.column
+            )?;
+            match &location.file {
+                Some(file) => writeln!(f, " {file}")?,
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for DebugInfo {
+    type Err = DebugInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate();
+        let mut info = DebugInfo::default();
+
+        match lines.next() {
+            Some((_, header)) if header == format!("{HEADER} {VERSION}") => {}
+            _ => return Err(DebugInfoError { line: 1 }),
+        }
+
+        for (index, line) in lines {
+            let error = DebugInfoError { line: index + 1 };
+            let mut fields = line.splitn(6, ' ');
+
+            match fields.next() {
+                Some("section") => info.sections.push(SectionRange {
+                    section: parse_section(fields.next()).ok_or(error.clone())?,
+                    start: parse_number(fields.next()).ok_or(error.clone())?,
+                    end: parse_number(fields.next()).ok_or(error)?,
+                }),
+                Some("label") => {
+                    let section = parse_section(fields.next()).ok_or(error.clone())?;
+                    let address = parse_number(fields.next()).ok_or(error.clone())?;
+                    // Everything after the address is the name
+                    let name = line.splitn(4, ' ').nth(3).ok_or(error)?;
+
+                    info.labels.push(DebugLabel {
+                        name: String::from(name),
+                        section,
+                        address,
+                    });
+                }
+                Some("range") => info.ranges.push(SourceRange {
+                    start: parse_number(fields.next()).ok_or(error.clone())?,
+                    end: parse_number(fields.next()).ok_or(error.clone())?,
+                    location: SourceLocation {
+                        line: parse_number(fields.next()).ok_or(error.clone())?,
+                        column: parse_number(fields.next()).ok_or(error)?,
+                        file: fields.next().map(String::from),
+                    },
+                }),
+                Some("") => {}
+                _ => return Err(error),
+            }
+        }
+
+        info.ranges.sort_by_key(|range| range.start);
+
+        Ok(info)
+    }
+}
+
+fn parse_section(field: Option<&str>) -> Option<Section> {
+    match field? {
+        "code" => Some(Section::Code),
+        "data" => Some(Section::Data),
+        _ => None,
+    }
+}
+
+fn parse_number<T: FromStr>(field: Option<&str>) -> Option<T> {
+    field?.parse().ok()
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+impl Display for DebugInfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "malformed debug info on line {}", self.line)
+    }
+}
+
+impl std::error::Error for DebugInfoError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assembler::Assembler;
+
+    fn assemble(source: &str) -> DebugInfo {
+        let mut assembler = Assembler::new();
+        assembler.generate_debug_info = true;
+        assembler.assemble(source).unwrap();
+
+        assembler.debug_info.unwrap()
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let info = assemble(
+            "main: LI $1 #70000\n\
+             .data\n\
+             name: .asciiz \"hi\"\n\
+             .code\n\
+             .loop: DEC $1\n\
+             HLT",
+        );
+
+        assert_eq!(info.ranges.len(), 3);
+        assert_eq!(info.location(0).map(|l| l.line), Some(1));
+        assert_eq!(info.location(23).map(|l| l.line), Some(1));
+        assert_eq!(info.location(24).map(|l| l.line), Some(5));
+        assert_eq!(info.location(29).map(|l| (l.line, l.column)), Some((6, 1)));
+        assert_eq!(info.location(32), None);
+
+        assert_eq!(info.label(0), Some(("main", 0)));
+        assert_eq!(info.label(30), Some(("name.loop", 6)));
+        assert_eq!(
+            info.sections,
+            vec![
+                SectionRange {
+                    section: Section::Code,
+                    start: 0,
+                    end: 24
+                },
+                SectionRange {
+                    section: Section::Data,
+                    start: 0,
+                    end: 3
+                },
+                SectionRange {
+                    section: Section::Code,
+                    start: 24,
+                    end: 32
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sidecar_format() {
+        let mut info = assemble("start: LOAD $1 #1\nHLT");
+        info.ranges[1].location.file = Some(String::from("my program.sasm"));
+
+        let text = info.to_string();
+        assert_eq!(
+            text,
+            "serus-debug 1\n\
+             section code 0 8\n\
+             label code 0 start\n\
+             range 0 4 1 1\n\
+             range 4 8 2 1 my program.sasm\n"
+        );
+        assert_eq!(text.parse(), Ok(info));
+
+        assert_eq!(
+            "serus-debug 2\n".parse::<DebugInfo>(),
+            Err(DebugInfoError { line: 1 })
+        );
+        assert_eq!(
+            "serus-debug 1\nrange 0 four 1 1\n".parse::<DebugInfo>(),
+            Err(DebugInfoError { line: 2 })
+        );
+        assert_eq!(
+            DebugInfo::sidecar_path("out.sbc"),
+            PathBuf::from("out.sbc.map")
+        );
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use crate::vm::{Fault, VM};
+
+/// Runs a VM under control of breakpoints and watchpoints, a few
+/// instructions at a time
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    /// Every instruction that was asked for ran
+    Step,
+    /// The next instruction to execute has a breakpoint on it
+    Breakpoint {
+        pc: usize,
+    },
+    /// The instruction at `pc` wrote to a watched location
+    Watchpoint {
+        id: usize,
+        pc: usize,
+        /// `None` for heap bytes that didn't exist before
+        old: Option<i32>,
+        new: i32,
+    },
+    /// `run_until` got to its address
+    Reached {
+        pc: usize,
+    },
+    Halted,
+    /// Execution moved past the end of the program
+    End,
+    Fault(Fault),
+}
+
+/// A location to stop at when it is written to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Watchpoint {
+    Register {
+        register: usize,
+        condition: Condition,
+    },
+    /// A byte of the heap. The heap is only compared before and after each
+    /// instruction, so writing the value a byte already holds isn't seen.
+    Heap {
+        address: usize,
+        condition: Condition,
+    },
+}
+
+/// What the value written to a watched location has to be to stop
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Condition {
+    /// Any write
+    Write,
+    Equal(i32),
+    NotEqual(i32),
+    Less(i32),
+    Greater(i32),
+}
+
+impl Condition {
+    pub fn holds(&self, value: i32) -> bool {
+        match *self {
+            Condition::Write => true,
+            Condition::Equal(other) => value == other,
+            Condition::NotEqual(other) => value != other,
+            Condition::Less(other) => value < other,
+            Condition::Greater(other) => value > other,
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            next_watchpoint: 1,
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Returns false if there already was a breakpoint at `pc`
+    pub fn add_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    /// Returns false if there was no breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Addresses with a breakpoint, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Adds the watchpoint and returns the id it can be removed with
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.insert(id, watchpoint);
+
+        id
+    }
+
+    /// Returns false if there was no watchpoint with the id
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    /// Watchpoints with their ids, in the order they were added
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> + '_ {
+        self.watchpoints
+            .iter()
+            .map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    /// Executes up to `count` instructions, stopping early at a breakpoint
+    /// or watchpoint. The instruction at the current pc always runs, so
+    /// stepping off a breakpoint doesn't stop at it again.
+    pub fn step(&mut self, count: usize) -> StopReason {
+        for i in 0..count {
+            let pc = self.vm.pc();
+            if i > 0 && self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint { pc };
+            }
+            if let Some(reason) = self.execute() {
+                return reason;
+            }
+        }
+
+        StopReason::Step
+    }
+
+    /// Runs until a breakpoint or watchpoint is reached or the program stops
+    pub fn resume(&mut self) -> StopReason {
+        self.run_to(None)
+    }
+
+    /// Executes the next instruction. When it jumps away, execution goes on
+    /// until it comes back to the instruction after it, so a jump to a
+    /// routine that jumps back runs as one step.
+    pub fn step_over(&mut self) -> StopReason {
+        let next = self.vm.pc() + crate::instruction::INSTRUCTION_SIZE;
+
+        match self.run_to(Some(next)) {
+            StopReason::Reached { .. } => StopReason::Step,
+            reason => reason,
+        }
+    }
+
+    /// Runs until the pc gets to `pc`, or something else stops it first
+    pub fn run_until(&mut self, pc: usize) -> StopReason {
+        self.run_to(Some(pc))
+    }
+
+    /// Resets the VM, the program, breakpoints and watchpoints are kept
+    pub fn reset(&mut self) {
+        self.vm.reset();
+    }
+
+    fn run_to(&mut self, target: Option<usize>) -> StopReason {
+        loop {
+            if let Some(reason) = self.execute() {
+                return reason;
+            }
+
+            let pc = self.vm.pc();
+            if target == Some(pc) {
+                return StopReason::Reached { pc };
+            }
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint { pc };
+            }
+        }
+    }
+
+    /// Executes one instruction, returns why execution has to stop after it
+    fn execute(&mut self) -> Option<StopReason> {
+        let pc = self.vm.pc();
+        if pc >= self.vm.program.len() {
+            return Some(StopReason::End);
+        }
+
+        // What the watched locations held before the instruction
+        let before: Vec<Option<i32>> = self
+            .watchpoints
+            .values()
+            .map(|watchpoint| self.watched_value(watchpoint))
+            .collect();
+
+        // Register writes are taken from the VM's history, which is kept for
+        // this one instruction when it is off
+        let borrows_history = self.vm.history_size() == 0
+            && self
+                .watchpoints
+                .values()
+                .any(|watchpoint| matches!(watchpoint, Watchpoint::Register { .. }));
+        if borrows_history {
+            self.vm.set_history_size(1);
+        }
+        let done = self.vm.execute_instruction();
+        let written = self.vm.last_written_registers().unwrap_or_default();
+        if borrows_history {
+            self.vm.set_history_size(0);
+        }
+
+        if done {
+            return Some(match self.vm.fault() {
+                Some(fault) => StopReason::Fault(fault.clone()),
+                None if self.vm.is_halted() => StopReason::Halted,
+                None => StopReason::End,
+            });
+        }
+
+        for ((id, watchpoint), old) in self.watchpoints.iter().zip(before) {
+            let new = match self.watched_value(watchpoint) {
+                Some(new) => new,
+                None => continue,
+            };
+            let (written, condition) = match *watchpoint {
+                Watchpoint::Register {
+                    register,
+                    condition,
+                } => (written.contains(&register), condition),
+                Watchpoint::Heap { condition, .. } => (old != Some(new), condition),
+            };
+
+            if written && condition.holds(new) {
+                return Some(StopReason::Watchpoint {
+                    id: *id,
+                    pc,
+                    old,
+                    new,
+                });
+            }
+        }
+
+        None
+    }
+
+    fn watched_value(&self, watchpoint: &Watchpoint) -> Option<i32> {
+        match *watchpoint {
+            Watchpoint::Register { register, .. } => self.vm.registers.get(register).copied(),
+            Watchpoint::Heap { address, .. } => {
+                self.vm.heap().get(address).map(|byte| *byte as i32)
+            }
+        }
+    }
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "stepped"),
+            StopReason::Breakpoint { pc } => write!(f, "breakpoint at {pc}"),
+            StopReason::Watchpoint {
+                id,
+                pc,
+                old: Some(old),
+                new,
+            } => write!(
+                f,
+                "watchpoint {id}: {old} -> {new} by the instruction at {pc}"
+            ),
+            StopReason::Watchpoint {
+                id,
+                pc,
+                old: None,
+                new,
+            } => write!(
+                f,
+                "watchpoint {id}: set to {new} by the instruction at {pc}"
+            ),
+            StopReason::Reached { pc } => write!(f, "reached {pc}"),
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::End => write!(f, "reached the end of the program"),
+            StopReason::Fault(fault) => write!(f, "{fault}"),
+        }
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let condition = match self {
+            Watchpoint::Register {
+                register,
+                condition,
+            } => {
+                write!(f, "${register}")?;
+                condition
+            }
+            Watchpoint::Heap { address, condition } => {
+                write!(f, "heap[{address}]")?;
+                condition
+            }
+        };
+
+        match condition {
+            Condition::Write => Ok(()),
+            Condition::Equal(value) => write!(f, " == {value}"),
+            Condition::NotEqual(value) => write!(f, " != {value}"),
+            Condition::Less(value) => write!(f, " < {value}"),
+            Condition::Greater(value) => write!(f, " > {value}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assembler::Assembler, vm::FaultKind};
+
+    fn debugger(source: &str) -> Debugger {
+        let mut vm = VM::new();
+        vm.program = Assembler::new().assemble(source).unwrap();
+
+        Debugger::new(vm)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger("LOAD $1 #2\nINC $1\nINC $1\nHLT");
+
+        assert_eq!(debugger.step(2), StopReason::Step);
+        assert_eq!(debugger.vm().registers[1], 3);
+        assert_eq!(debugger.vm().pc(), 8);
+
+        assert_eq!(debugger.step(5), StopReason::Halted);
+        assert_eq!(debugger.vm().registers[1], 4);
+
+        debugger.reset();
+        assert_eq!(debugger.vm().registers[1], 0);
+        assert_eq!(debugger.vm().pc(), 0);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger(
+            "LOAD $1 #3\n\
+             loop: DEC $1\n\
+             GT $3 $1 $0\n\
+             LOAD $2 @loop\n\
+             JEQ $2 $3",
+        );
+
+        assert!(debugger.add_breakpoint(4));
+        assert!(!debugger.add_breakpoint(4));
+        assert_eq!(debugger.resume(), StopReason::Breakpoint { pc: 4 });
+        assert_eq!(debugger.resume(), StopReason::Breakpoint { pc: 4 });
+        assert_eq!(debugger.vm().registers[1], 2);
+
+        // Stepping runs the instruction under the breakpoint first
+        assert_eq!(debugger.step(5), StopReason::Breakpoint { pc: 4 });
+        assert_eq!(debugger.vm().registers[1], 1);
+
+        assert!(debugger.remove_breakpoint(4));
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert_eq!(debugger.resume(), StopReason::End);
+        assert_eq!(debugger.vm().registers[1], 0);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger(
+            "LOAD $1 #3\n\
+             LOAD $2 #3\n\
+             loop: DEC $1\n\
+             GT $3 $1 $0\n\
+             LOAD $4 @loop\n\
+             JEQ $4 $3\n\
+             ALOC $2",
+        );
+
+        let id = debugger.add_watchpoint(Watchpoint::Register {
+            register: 2,
+            condition: Condition::Write,
+        });
+        // Loading the value a register already holds is still a write
+        debugger.vm_mut().registers[2] = 3;
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint {
+                id,
+                pc: 4,
+                old: Some(3),
+                new: 3
+            }
+        );
+
+        assert!(debugger.remove_watchpoint(id));
+        let id = debugger.add_watchpoint(Watchpoint::Register {
+            register: 1,
+            condition: Condition::Less(2),
+        });
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint {
+                id,
+                pc: 8,
+                old: Some(2),
+                new: 1
+            }
+        );
+
+        debugger.remove_watchpoint(id);
+        let id = debugger.add_watchpoint(Watchpoint::Heap {
+            address: 2,
+            condition: Condition::Write,
+        });
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint {
+                id,
+                pc: 24,
+                old: None,
+                new: 0
+            }
+        );
+        assert_eq!(debugger.watchpoints().count(), 1);
+        // Watching registers doesn't leave the history on
+        assert_eq!(debugger.vm().history_size(), 0);
+    }
+
+    #[test]
+    fn test_step_over_and_run_until() {
+        let mut debugger = debugger(
+            "LOAD $5 @routine\n\
+             JMP $5\n\
+             back: INC $1\n\
+             HLT\n\
+             routine: INC $2\n\
+             INC $2\n\
+             LOAD $5 @back\n\
+             JMP $5",
+        );
+
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.vm().pc(), 4);
+
+        // The jump goes to the routine, which comes back to the next line
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.vm().pc(), 8);
+        assert_eq!(debugger.vm().registers[2], 2);
+
+        debugger.reset();
+        assert_eq!(debugger.run_until(20), StopReason::Reached { pc: 20 });
+        assert_eq!(debugger.vm().registers[2], 1);
+        assert_eq!(debugger.run_until(100), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut debugger = debugger("DIV $1 $2 $3");
+
+        match debugger.resume() {
+            StopReason::Fault(fault) => assert_eq!(fault.kind, FaultKind::DivisionByZero),
+            reason => panic!("expected a fault, got {reason:?}"),
+        }
+        assert_eq!(debugger.vm().pc(), 0);
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::BTreeSet, fmt::Display};
+
+use crate::instruction::{loaded_jump, Instruction, Opcode, OperandKind, INSTRUCTION_SIZE};
+
+/// One line of disassembled code
+#[derive(Debug, PartialEq, Clone)]
+pub enum Line {
+    Instruction {
+        opcode: Opcode,
+        operands: Vec<Operand>,
+    },
+    /// 4 bytes that don't form a valid instruction, written as `.word`
+    Word(u32),
+    /// A byte at the end of a program that isn't a multiple of 4 bytes long
+    Byte(u8),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+    Register(u8),
+    Immediate(u16),
+    /// An immediate that is used as the target of a jump
+    Label(u16),
+}
+
+/// Decodes the program into lines together with the address they start at.
+/// Bytes that aren't a valid instruction are kept as data, so that every
+/// program can be written back as assembly.
+pub fn decode(program: &[u8]) -> Vec<(usize, Line)> {
+    let decoded: Vec<Option<(Instruction, Vec<Operand>)>> = program
+        .chunks_exact(INSTRUCTION_SIZE)
+        .map(|bytes| decode_instruction(bytes.try_into().expect("chunks are exact")))
+        .collect();
+    let instructions: Vec<Option<Instruction>> = decoded
+        .iter()
+        .map(|decoded| decoded.as_ref().map(|(instruction, _)| *instruction))
+        .collect();
+
+    let mut lines: Vec<(usize, Line)> = decoded
+        .into_iter()
+        .zip(program.chunks_exact(INSTRUCTION_SIZE))
+        .enumerate()
+        .map(|(i, (decoded, bytes))| {
+            let line = match decoded {
+                Some((instruction, operands)) => Line::Instruction {
+                    opcode: instruction.opcode(),
+                    operands,
+                },
+                None => Line::Word(u32::from_be_bytes(
+                    bytes.try_into().expect("chunks are exact"),
+                )),
+            };
+            (i * INSTRUCTION_SIZE, line)
+        })
+        .collect();
+
+    let end = program.len() - program.len() % INSTRUCTION_SIZE;
+    lines.extend(
+        program[end..]
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| (end + offset, Line::Byte(*byte))),
+    );
+
+    // Only absolute jumps are labelled, the value of a relative one isn't
+    // the address it goes to
+    for (index, (_, line)) in lines.iter_mut().enumerate().take(instructions.len()) {
+        if let Some((jump, target)) = loaded_jump(&instructions, index) {
+            let absolute = matches!(
+                instructions[jump].map(|jump| jump.opcode()),
+                Some(Opcode::JMP | Opcode::JEQ | Opcode::JNEQ)
+            );
+            let on_instruction = (target as usize).is_multiple_of(INSTRUCTION_SIZE)
+                && target as usize <= program.len();
+            if absolute && on_instruction {
+                if let Line::Instruction { operands, .. } = line {
+                    operands[1] = Operand::Label(target as u16);
+                }
+            }
+        }
+    }
+
+    lines
+}
+
+/// Turns a program back into assembly that assembles to the same bytes.
+/// Values that are loaded into a register that is then jumped to are
+/// replaced by labels.
+pub fn disassemble(program: &[u8]) -> String {
+    let lines = decode(program);
+    let labels: BTreeSet<usize> = lines
+        .iter()
+        .flat_map(|(_, line)| match line {
+            Line::Instruction { operands, .. } => operands.clone(),
+            _ => vec![],
+        })
+        .filter_map(|operand| match operand {
+            Operand::Label(target) => Some(target as usize),
+            _ => None,
+        })
+        .collect();
+
+    let mut output = String::new();
+    for (address, line) in &lines {
+        if labels.contains(address) {
+            output.push_str(&format!("{}:\n", label_name(*address)));
+        }
+        output.push_str(&format!("    {line}\n"));
+    }
+
+    if labels.contains(&program.len()) {
+        output.push_str(&format!("{}:\n", label_name(program.len())));
+    }
+
+    output
+}
+
+fn label_name(address: usize) -> String {
+    format!("L{address}")
+}
+
+/// Decodes an instruction with its operands, `None` if the bytes aren't an
+/// instruction the assembler could have written
+fn decode_instruction(bytes: [u8; INSTRUCTION_SIZE]) -> Option<(Instruction, Vec<Operand>)> {
+    let instruction = Instruction::decode_checked(bytes).ok()?;
+
+    let mut operands = vec![];
+    let mut position = 1;
+    for kind in instruction.opcode().operands() {
+        match kind {
+            OperandKind::Register => {
+                operands.push(Operand::Register(bytes[position]));
+                position += 1;
+            }
+            OperandKind::Immediate => {
+                let value = u16::from_be_bytes([bytes[position], bytes[position + 1]]);
+                operands.push(Operand::Immediate(value));
+                position += 2;
+            }
+        }
+    }
+
+    // The assembler always writes zeros after the operands
+    if bytes[position..].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+
+    Some((instruction, operands))
+}
+
+impl Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Line::Instruction { opcode, operands } => {
+                write!(f, "{}", opcode.to_string().to_uppercase())?;
+                for operand in operands {
+                    write!(f, " {operand}")?;
+                }
+
+                Ok(())
+            }
+            // Written signed so the value always fits the assembler's numbers
+            Line::Word(word) if (*word as i32) < 0 => write!(f, ".word ~#{}", !*word),
+            Line::Word(word) => write!(f, ".word #{word}"),
+            Line::Byte(byte) => write!(f, ".byte #{byte}"),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "${register}"),
+            Operand::Immediate(value) => write!(f, "#{value}"),
+            Operand::Label(target) => write!(f, "@{}", label_name(*target as usize)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assembler::Assembler;
+
+    fn round_trip(program: &[u8]) {
+        let source = disassemble(program);
+        let assembled = Assembler::new().assemble(&source);
+
+        assert_eq!(assembled.as_deref(), Ok(program), "{source}");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = Assembler::new()
+            .assemble(
+                "LOAD $0 #3\n\
+                 loop: DEC $0\n\
+                 BLT $zero $0 @loop\n\
+                 ADD $1 $0 $2\n\
+                 HLT",
+            )
+            .unwrap();
+
+        assert_eq!(
+            disassemble(&program),
+            "    LOAD $0 #3\n\
+             L4:\n    \
+                 DEC $0\n    \
+                 LT $30 $0 $0\n    \
+                 LOAD $31 @L4\n    \
+                 JEQ $31 $30\n    \
+                 ADD $1 $0 $2\n    \
+                 HLT\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_invalid_bytes() {
+        let program = [
+            254, 0, 0, 0, 6, 40, 0, 0, 18, 0, 0, 1, 255, 255, 255, 255, 5, 0,
+        ];
+
+        assert_eq!(
+            disassemble(&program),
+            "    .word ~#33554431\n    \
+                 .word #103284736\n    \
+                 .word #301989889\n    \
+                 .word ~#0\n    \
+                 .byte #5\n    \
+                 .byte #0\n"
+        );
+        round_trip(&program);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = Assembler::new()
+            .assemble(
+                "start: LI $1 #100000\n\
+                 B @end\n\
+                 JMP $1\n\
+                 LOAD $2 @start\n\
+                 JNEQ $2 $1\n\
+                 .word @end\n\
+                 end: HLT",
+            )
+            .unwrap();
+        round_trip(&program);
+
+        // Any sequence of bytes has to survive the trip
+        let mut seed: u32 = 7;
+        for length in 0..64 {
+            let program: Vec<u8> = (0..length)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    // Favour small values so real instructions come up often
+                    ((seed >> 16) % 24) as u8
+                })
+                .collect();
+            round_trip(&program);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{instruction::Opcode, vm::Fault};
+
+/// Callbacks a VM makes while it executes, for tools like profilers and
+/// coverage that watch a program run. Every method does nothing by default,
+/// so an implementation only overrides what it needs. The hooks are a type
+/// parameter of the VM, a VM without them uses `NoHooks` and compiles to the
+/// same code as if there were no hooks.
+#[allow(unused_variables)]
+pub trait VmHooks {
+    /// Called with the instruction at `pc` before it is executed
+    #[inline(always)]
+    fn before_instruction(&mut self, pc: usize, opcode: Opcode) {}
+
+    /// Called after the instruction at `pc` executed without faulting
+    #[inline(always)]
+    fn after_instruction(&mut self, pc: usize, opcode: Opcode) {}
+
+    /// Called for every register an instruction writes, also when the value
+    /// doesn't change
+    #[inline(always)]
+    fn on_register_write(&mut self, register: usize, old: i32, new: i32) {}
+
+    /// Called for every byte an instruction stores in the heap. So far only
+    /// `ALOC` does, it fills the bytes it adds with zeros, after `on_alloc`.
+    /// Those bytes didn't exist before and are reported with an old value of
+    /// 0.
+    #[inline(always)]
+    fn on_heap_write(&mut self, address: usize, old: u8, new: u8) {}
+
+    /// Called when `ALOC` resizes the heap
+    #[inline(always)]
+    fn on_alloc(&mut self, old_size: usize, new_size: usize) {}
+
+    /// Called when an instruction faults, before the VM stops
+    #[inline(always)]
+    fn on_fault(&mut self, fault: &Fault) {}
+}
+
+/// The hooks of a VM that isn't being watched
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NoHooks;
+
+impl VmHooks for NoHooks {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assembler::Assembler, vm::VM};
+
+    /// Counts how often each instruction runs, like a profiler would
+    #[derive(Default)]
+    struct Profile {
+        counts: Vec<usize>,
+        opcodes: Vec<Opcode>,
+        register_writes: Vec<(usize, i32, i32)>,
+        allocs: Vec<(usize, usize)>,
+        heap_writes: Vec<(usize, u8, u8)>,
+        faults: Vec<usize>,
+    }
+
+    impl VmHooks for Profile {
+        fn before_instruction(&mut self, pc: usize, opcode: Opcode) {
+            let index = pc / 4;
+            if self.counts.len() <= index {
+                self.counts.resize(index + 1, 0);
+            }
+            self.counts[index] += 1;
+            self.opcodes.push(opcode);
+        }
+
+        fn on_register_write(&mut self, register: usize, old: i32, new: i32) {
+            self.register_writes.push((register, old, new));
+        }
+
+        fn on_alloc(&mut self, old_size: usize, new_size: usize) {
+            self.allocs.push((old_size, new_size));
+        }
+
+        fn on_heap_write(&mut self, address: usize, old: u8, new: u8) {
+            self.heap_writes.push((address, old, new));
+        }
+
+        fn on_fault(&mut self, fault: &Fault) {
+            self.faults.push(fault.pc);
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut vm = VM::with_hooks(Profile::default());
+        vm.program = Assembler::new()
+            .assemble(
+                "LOAD $1 #2\n\
+                 loop: DEC $1\n\
+                 GT $2 $1 $0\n\
+                 LOAD $3 @loop\n\
+                 JEQ $3 $2\n\
+                 ALOC $3\n\
+                 DIV $4 $1 $0",
+            )
+            .unwrap();
+        assert!(vm.run().is_err());
+
+        let profile = vm.hooks();
+        assert_eq!(profile.counts, vec![1, 2, 2, 2, 2, 1, 1]);
+        assert_eq!(profile.opcodes.last(), Some(&Opcode::DIV));
+        assert_eq!(
+            &profile.register_writes[..3],
+            &[(1, 0, 2), (1, 2, 1), (2, 0, 1)]
+        );
+        // Writes of an unchanged value are reported too
+        assert_eq!(profile.register_writes[6], (3, 4, 4));
+        assert_eq!(profile.allocs, vec![(0, 4)]);
+        assert_eq!(
+            profile.heap_writes,
+            vec![(0, 0, 0), (1, 0, 0), (2, 0, 0), (3, 0, 0)]
+        );
+        assert_eq!(profile.faults, vec![24]);
+    }
+}
@@ -2,6 +2,8 @@
 
 use std::{fmt::Display, str::FromStr};
 
+use crate::vm::REGISTER_COUNT;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode {
     LOAD,
@@ -27,15 +29,209 @@ pub enum Opcode {
     IGL,
 }
 
-#[derive(Debug, PartialEq)]
+/// Every instruction is encoded in this many bytes, unused bytes are zero
+pub const INSTRUCTION_SIZE: usize = 4;
+
+/// What an operand byte, or pair of bytes, following the opcode holds
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    /// One byte naming a register
+    Register,
+    /// A 16-bit big-endian value
+    Immediate,
+}
+
+impl Opcode {
+    /// Every opcode that can be written in assembly, in encoding order
+    pub const ALL: [Opcode; 20] = [
+        Opcode::LOAD,
+        Opcode::ADD,
+        Opcode::DIV,
+        Opcode::MUL,
+        Opcode::SUB,
+        Opcode::HLT,
+        Opcode::JMP,
+        Opcode::JMPB,
+        Opcode::JMPF,
+        Opcode::EQ,
+        Opcode::NEQ,
+        Opcode::GT,
+        Opcode::LT,
+        Opcode::GTQ,
+        Opcode::LTQ,
+        Opcode::JEQ,
+        Opcode::JNEQ,
+        Opcode::ALOC,
+        Opcode::INC,
+        Opcode::DEC,
+    ];
+
+    /// Whether the first operand is a register the instruction writes to
+    pub fn writes_register(&self) -> bool {
+        !matches!(
+            self,
+            Opcode::HLT
+                | Opcode::JMP
+                | Opcode::JMPB
+                | Opcode::JMPF
+                | Opcode::JEQ
+                | Opcode::JNEQ
+                | Opcode::ALOC
+                | Opcode::IGL
+        )
+    }
+
+    /// The operands that follow the opcode, in the order they are encoded
+    pub fn operands(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+
+        match self {
+            Opcode::LOAD => &[Register, Immediate],
+            Opcode::ADD
+            | Opcode::DIV
+            | Opcode::MUL
+            | Opcode::SUB
+            | Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTQ
+            | Opcode::LTQ => &[Register, Register, Register],
+            Opcode::JEQ | Opcode::JNEQ => &[Register, Register],
+            Opcode::JMP
+            | Opcode::JMPB
+            | Opcode::JMPF
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::DEC => &[Register],
+            Opcode::HLT | Opcode::IGL => &[],
+        }
+    }
+}
+
+/// Why 4 bytes aren't an instruction that can execute, with the byte that
+/// is wrong
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DecodeError {
+    IllegalOpcode { opcode: u8 },
+    InvalidRegister { register: u8 },
+}
+
+/// A decoded instruction, the opcode with the 3 bytes that follow it
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
     opcode: Opcode,
+    operands: [u8; INSTRUCTION_SIZE - 1],
 }
 
 impl Instruction {
+    /// An instruction whose operand bytes are all zero
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            operands: [0; INSTRUCTION_SIZE - 1],
+        }
+    }
+
+    /// Decodes the bytes without checking them, unknown opcodes become
+    /// `IGL`
+    pub fn decode(bytes: [u8; INSTRUCTION_SIZE]) -> Instruction {
+        Instruction {
+            opcode: Opcode::from(bytes[0]),
+            operands: [bytes[1], bytes[2], bytes[3]],
+        }
+    }
+
+    /// Decodes the bytes and checks that the opcode is known and that every
+    /// register operand exists, so the instruction can execute without
+    /// further checks
+    pub fn decode_checked(bytes: [u8; INSTRUCTION_SIZE]) -> Result<Instruction, DecodeError> {
+        let instruction = Instruction::decode(bytes);
+        if instruction.opcode == Opcode::IGL {
+            return Err(DecodeError::IllegalOpcode { opcode: bytes[0] });
+        }
+
+        let mut position = 0;
+        for kind in instruction.opcode.operands() {
+            match kind {
+                OperandKind::Register => {
+                    let register = instruction.operands[position];
+                    if register as usize >= REGISTER_COUNT {
+                        return Err(DecodeError::InvalidRegister { register });
+                    }
+                    position += 1;
+                }
+                OperandKind::Immediate => position += 2,
+            }
+        }
+
+        Ok(instruction)
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    /// The byte of the first operand, the result register of most
+    /// instructions
+    pub fn a(&self) -> usize {
+        self.operands[0] as usize
     }
+
+    pub fn b(&self) -> usize {
+        self.operands[1] as usize
+    }
+
+    pub fn c(&self) -> usize {
+        self.operands[2] as usize
+    }
+
+    /// The 16-bit value that follows the register of a `LOAD`
+    pub fn immediate(&self) -> u16 {
+        u16::from_be_bytes([self.operands[1], self.operands[2]])
+    }
+}
+
+/// Decodes every 4 bytes of the program, `None` where they aren't a valid
+/// instruction. Bytes at the end that don't make up a whole instruction
+/// are left out.
+pub fn predecode(program: &[u8]) -> Vec<Option<Instruction>> {
+    program
+        .chunks_exact(INSTRUCTION_SIZE)
+        .map(|bytes| Instruction::decode_checked(bytes.try_into().expect("chunks are exact")).ok())
+        .collect()
+}
+
+/// If the instruction at `index` is a `LOAD` whose value a jump uses before
+/// the register is written again or control leaves the straight line of
+/// code after it, returns the index of the jump and the address it goes
+/// to. `None` stands for bytes that aren't an instruction, which end the
+/// straight line.
+pub fn loaded_jump(instructions: &[Option<Instruction>], index: usize) -> Option<(usize, i64)> {
+    let load = instructions[index]?;
+    if load.opcode() != Opcode::LOAD {
+        return None;
+    }
+    let (register, value) = (load.a(), load.immediate() as i64);
+
+    for (jump, instruction) in instructions.iter().enumerate().skip(index + 1) {
+        let instruction = (*instruction)?;
+        let first = instruction.a() == register;
+        // Relative jumps count from the byte after their register
+        let relative_to = (jump * INSTRUCTION_SIZE + 2) as i64;
+
+        match instruction.opcode() {
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ if first => return Some((jump, value)),
+            Opcode::JMPB if first => return Some((jump, relative_to - value)),
+            Opcode::JMPF if first => return Some((jump, relative_to + value)),
+            Opcode::JMP | Opcode::JMPB | Opcode::JMPF | Opcode::JEQ | Opcode::JNEQ => return None,
+            Opcode::HLT | Opcode::IGL => return None,
+            opcode if opcode.writes_register() && first => return None,
+            _ => {}
+        }
+    }
+
+    None
 }
 
 impl From<u8> for Opcode {
@@ -130,19 +326,23 @@ impl FromStr for Opcode {
             "load" => Ok(Opcode::LOAD),
             "add" => Ok(Opcode::ADD),
             "div" => Ok(Opcode::DIV),
+            "mul" => Ok(Opcode::MUL),
             "sub" => Ok(Opcode::SUB),
             "hlt" => Ok(Opcode::HLT),
             "jmp" => Ok(Opcode::JMP),
             "jmpb" => Ok(Opcode::JMPB),
             "jmpf" => Ok(Opcode::JMPF),
             "eq" => Ok(Opcode::EQ),
-            "NEQ" => Ok(Opcode::NEQ),
+            "neq" => Ok(Opcode::NEQ),
             "gt" => Ok(Opcode::GT),
-            "lq" => Ok(Opcode::LT),
+            "lt" => Ok(Opcode::LT),
             "gtq" => Ok(Opcode::GTQ),
             "ltq" => Ok(Opcode::LTQ),
             "jeq" => Ok(Opcode::JEQ),
             "jneq" => Ok(Opcode::JNEQ),
+            "aloc" => Ok(Opcode::ALOC),
+            "inc" => Ok(Opcode::INC),
+            "dec" => Ok(Opcode::DEC),
             _ => Err(()),
         }
     }
@@ -152,7 +352,7 @@ impl FromStr for Opcode {
 mod tests {
     use crate::instruction::Instruction;
 
-    use super::Opcode;
+    use super::{predecode, DecodeError, Opcode};
 
     #[test]
     fn test_create_hlt() {
@@ -165,4 +365,52 @@ mod tests {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_decode_instruction() {
+        let instruction = Instruction::decode([0, 3, 1, 244]);
+        assert_eq!(instruction.opcode(), Opcode::LOAD);
+        assert_eq!(instruction.a(), 3);
+        assert_eq!(instruction.immediate(), 500);
+
+        assert_eq!(Instruction::decode([200, 0, 0, 0]).opcode(), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_decode_checked() {
+        assert_eq!(
+            Instruction::decode_checked([1, 1, 2, 3]),
+            Ok(Instruction::decode([1, 1, 2, 3]))
+        );
+        assert_eq!(
+            Instruction::decode_checked([200, 0, 0, 0]),
+            Err(DecodeError::IllegalOpcode { opcode: 200 })
+        );
+        assert_eq!(
+            Instruction::decode_checked([1, 1, 40, 3]),
+            Err(DecodeError::InvalidRegister { register: 40 })
+        );
+        // The immediate of a LOAD isn't a register
+        assert!(Instruction::decode_checked([0, 1, 255, 255]).is_ok());
+    }
+
+    #[test]
+    fn test_predecode() {
+        let program = [18, 1, 0, 0, 1, 0, 40, 2, 99, 0, 0, 0, 5, 0, 0, 0, 0, 1];
+        let instructions = predecode(&program);
+
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(instructions[0].map(|i| i.opcode()), Some(Opcode::INC));
+        assert_eq!(instructions[1], None);
+        assert_eq!(instructions[2], None);
+        assert_eq!(instructions[3].map(|i| i.opcode()), Some(Opcode::HLT));
+    }
+
+    #[test]
+    fn test_all_opcodes() {
+        for (code, opcode) in Opcode::ALL.iter().enumerate() {
+            assert_eq!(Opcode::from(code as u8), *opcode);
+        }
+        assert_eq!(Opcode::from(Opcode::ALL.len() as u8), Opcode::IGL);
+    }
 }
@@ -1,3 +1,11 @@
 pub mod assembler;
+pub mod debug_info;
+pub mod debugger;
+pub mod disassembler;
+pub mod hooks;
 pub mod instruction;
+pub mod snapshot;
+pub mod trace;
+mod undo;
+pub mod verifier;
 pub mod vm;
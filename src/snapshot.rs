@@ -0,0 +1,218 @@
+use std::fmt::Display;
+
+use crate::vm::REGISTER_COUNT;
+
+/// Bytes every snapshot starts with
+pub const MAGIC: [u8; 4] = *b"SVMS";
+
+/// Version of the format `Snapshot::to_bytes` writes
+pub const VERSION: u16 = 1;
+
+/// Everything a VM needs to continue a computation where it was stopped,
+/// taken with `VM::snapshot` and put back with `VM::restore`. Configuration
+/// like limits, costs, hooks and debug info isn't part of it.
+///
+/// The binary format stores numbers big-endian:
+///
+/// ```text
+/// magic "SVMS" | version u16 | flags u8 (bit 0: halted)
+/// pc u64 | remainder u32 | allocations u64 | 32 registers i32
+/// program length u64 | program | heap length u64 | heap
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    pub registers: [i32; REGISTER_COUNT],
+    pub pc: usize,
+    pub remainder: u32,
+    pub program: Vec<u8>,
+    pub heap: Vec<u8>,
+    /// Whether the last instruction was `HLT`
+    pub halted: bool,
+    /// Number of `ALOC`s executed, counted against `VmLimits`
+    pub allocations: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    NotASnapshot,
+    UnsupportedVersion {
+        version: u16,
+    },
+    /// The data ends before the snapshot does
+    Truncated,
+    /// There is data after the end of the snapshot
+    TrailingBytes,
+    /// A length or address doesn't fit in memory on this machine
+    TooLarge,
+}
+
+const HALTED: u8 = 1;
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(80 + 4 * REGISTER_COUNT + self.program.len());
+
+        bytes.extend(MAGIC);
+        bytes.extend(VERSION.to_be_bytes());
+        bytes.push(if self.halted { HALTED } else { 0 });
+        bytes.extend((self.pc as u64).to_be_bytes());
+        bytes.extend(self.remainder.to_be_bytes());
+        bytes.extend(self.allocations.to_be_bytes());
+        for register in self.registers {
+            bytes.extend(register.to_be_bytes());
+        }
+        for part in [&self.program, &self.heap] {
+            bytes.extend((part.len() as u64).to_be_bytes());
+            bytes.extend(part);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut reader = Reader { bytes };
+
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_be_bytes(reader.array()?);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let flags = reader.array::<1>()?[0];
+        let pc = reader.length()?;
+        let remainder = u32::from_be_bytes(reader.array()?);
+        let allocations = u64::from_be_bytes(reader.array()?);
+        let mut registers = [0; REGISTER_COUNT];
+        for register in &mut registers {
+            *register = i32::from_be_bytes(reader.array()?);
+        }
+        let length = reader.length()?;
+        let program = reader.take(length)?.to_vec();
+        let length = reader.length()?;
+        let heap = reader.take(length)?.to_vec();
+
+        if !reader.bytes.is_empty() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+
+        Ok(Snapshot {
+            registers,
+            pc,
+            remainder,
+            program,
+            heap,
+            halted: flags & HALTED != 0,
+            allocations,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < length {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let bytes = self.take(N)?;
+        Ok(bytes.try_into().expect("took N bytes"))
+    }
+
+    fn length(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(u64::from_be_bytes(self.array()?)).map_err(|_| SnapshotError::TooLarge)
+    }
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a VM snapshot"),
+            SnapshotError::UnsupportedVersion { version } => write!(
+                f,
+                "snapshot version {version} is not supported, only version {VERSION} is"
+            ),
+            SnapshotError::Truncated => write!(f, "the snapshot is cut off"),
+            SnapshotError::TrailingBytes => {
+                write!(f, "there is data after the end of the snapshot")
+            }
+            SnapshotError::TooLarge => write!(f, "the snapshot is too large for this machine"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assembler::Assembler, vm::VM};
+
+    #[test]
+    fn test_snapshot() {
+        let mut vm = VM::new();
+        vm.program = Assembler::new()
+            .assemble(
+                "LOAD $1 #7\n\
+                 LOAD $2 #2\n\
+                 DIV $3 $1 $2\n\
+                 ALOC $1\n\
+                 INC $3\n\
+                 HLT",
+            )
+            .unwrap();
+        for _ in 0..4 {
+            vm.run_once();
+        }
+
+        let bytes = vm.snapshot().to_bytes();
+        let mut restored = VM::new();
+        restored.restore(Snapshot::from_bytes(&bytes).unwrap());
+        assert_eq!(restored.snapshot(), vm.snapshot());
+        assert_eq!(restored.pc(), 16);
+        assert_eq!(restored.heap().len(), 7);
+
+        // Both continue the same way
+        vm.run().unwrap();
+        restored.run().unwrap();
+        assert_eq!(restored.snapshot(), vm.snapshot());
+        assert!(restored.is_halted());
+        assert_eq!(restored.registers[3], 4);
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        let bytes = VM::new().snapshot().to_bytes();
+
+        assert_eq!(
+            Snapshot::from_bytes(b"SBC\0"),
+            Err(SnapshotError::NotASnapshot)
+        );
+        let mut version = bytes.clone();
+        version[5] = 9;
+        assert_eq!(
+            Snapshot::from_bytes(&version),
+            Err(SnapshotError::UnsupportedVersion { version: 9 })
+        );
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Snapshot::from_bytes(&trailing),
+            Err(SnapshotError::TrailingBytes)
+        );
+    }
+}
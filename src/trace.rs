@@ -0,0 +1,323 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use crate::{debug_info::SourceLocation, disassembler::Line, vm::FaultKind};
+
+/// A sink shared between the VM and whoever reads what it collected. A VM
+/// that is cloned keeps tracing into the same sink.
+pub type Tracer = Arc<Mutex<dyn TraceSink + Send>>;
+
+/// Receives an event for every instruction a VM executes while tracing
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent);
+}
+
+/// One executed instruction and what it changed
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceEvent {
+    /// Address the instruction was read from
+    pub pc: usize,
+    pub instruction: Line,
+    pub changes: Vec<Change>,
+    /// Source of the instruction, when the VM has debug info for it
+    pub location: Option<SourceLocation>,
+    /// Set when the instruction faulted instead of executing
+    pub fault: Option<FaultKind>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Change {
+    Register {
+        register: usize,
+        old: i32,
+        new: i32,
+    },
+    Remainder {
+        old: u32,
+        new: u32,
+    },
+    /// `ALOC` grew the heap, the new bytes are zero
+    Heap {
+        old_size: usize,
+        new_size: usize,
+    },
+}
+
+/// Prints every event on its own line, like
+/// `0004  ADD $3 $1 $2   $3: 0 -> 7 ; main.sasm:2`
+pub struct Pretty<W: Write> {
+    writer: W,
+    error: Option<std::io::Error>,
+}
+
+/// Writes every event as a JSON object on its own line
+pub struct JsonLines<W: Write> {
+    writer: W,
+    error: Option<std::io::Error>,
+}
+
+/// Keeps the last `capacity` events in memory
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    capacity: usize,
+    events: VecDeque<TraceEvent>,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Register { register, old, new } => write!(f, "${register}: {old} -> {new}"),
+            Change::Remainder { old, new } => write!(f, "remainder: {old} -> {new}"),
+            Change::Heap { old_size, new_size } => {
+                write!(f, "heap: {old_size} -> {new_size} bytes")
+            }
+        }
+    }
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut line = format!("{:04x}  {:<20}", self.pc, self.instruction.to_string());
+        for change in &self.changes {
+            line.push_str(&format!(" {change}"));
+        }
+        if let Some(fault) = &self.fault {
+            line.push_str(&format!(" fault: {fault}"));
+        }
+        if let Some(location) = &self.location {
+            line.push_str(&format!(" ; {location}"));
+        }
+
+        write!(f, "{}", line.trim_end())
+    }
+}
+
+impl TraceEvent {
+    /// The event as a single line JSON object, fields that don't apply
+    /// are `null`
+    pub fn to_json(&self) -> String {
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|change| match change {
+                Change::Register { register, old, new } => {
+                    format!(r#"{{"register":{register},"old":{old},"new":{new}}}"#)
+                }
+                Change::Remainder { old, new } => {
+                    format!(r#"{{"remainder":true,"old":{old},"new":{new}}}"#)
+                }
+                Change::Heap { old_size, new_size } => {
+                    format!(r#"{{"heap":true,"old":{old_size},"new":{new_size}}}"#)
+                }
+            })
+            .collect();
+        let location = match &self.location {
+            Some(location) => json_string(&location.to_string()),
+            None => String::from("null"),
+        };
+        let fault = match &self.fault {
+            Some(fault) => json_string(&fault.to_string()),
+            None => String::from("null"),
+        };
+
+        format!(
+            r#"{{"pc":{},"instruction":{},"changes":[{}],"location":{},"fault":{}}}"#,
+            self.pc,
+            json_string(&self.instruction.to_string()),
+            changes.join(","),
+            location,
+            fault
+        )
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+impl<W: Write> Pretty<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// The error that stopped the output, nothing is written after one
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl Pretty<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+}
+
+impl<W: Write> TraceSink for Pretty<W> {
+    fn record(&mut self, event: &TraceEvent) {
+        if self.error.is_none() {
+            self.error = writeln!(self.writer, "{event}").err();
+        }
+    }
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// The error that stopped the output, nothing is written after one
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<W: Write> TraceSink for JsonLines<W> {
+    fn record(&mut self, event: &TraceEvent) {
+        if self.error.is_none() {
+            self.error = writeln!(self.writer, "{}", event.to_json()).err();
+        }
+    }
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The kept events, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &TraceEvent> {
+        self.events.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+impl TraceSink for RingBuffer {
+    fn record(&mut self, event: &TraceEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assembler::Assembler, vm::VM};
+
+    fn trace(source: &str, sink: Tracer) -> VM {
+        let mut vm = VM::new();
+        vm.program = Assembler::new().assemble(source).unwrap();
+        vm.set_tracer(Some(sink));
+        let _ = vm.run();
+
+        vm
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let buffer = Arc::new(Mutex::new(RingBuffer::new(3)));
+        trace(
+            "LOAD $1 #7\nLOAD $2 #2\nDIV $3 $1 $2\nALOC $2\nHLT",
+            buffer.clone(),
+        );
+
+        let buffer = buffer.lock().unwrap();
+        let events: Vec<&TraceEvent> = buffer.events().collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].pc, 8);
+        assert_eq!(
+            events[0].changes,
+            vec![
+                Change::Register {
+                    register: 3,
+                    old: 0,
+                    new: 3
+                },
+                Change::Remainder { old: 0, new: 1 }
+            ]
+        );
+        assert_eq!(
+            events[1].changes,
+            vec![Change::Heap {
+                old_size: 0,
+                new_size: 2
+            }]
+        );
+        assert_eq!(events[2].instruction.to_string(), "HLT");
+        assert!(events[2].changes.is_empty());
+    }
+
+    #[test]
+    fn test_writers() {
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let source = "LOAD $1 #7\nDIV $3 $1 $2";
+        let output = Output::default();
+        trace(source, Arc::new(Mutex::new(Pretty::new(output.clone()))));
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "0000  LOAD $1 #7           $1: 0 -> 7\n\
+             0004  DIV $3 $1 $2         fault: division by zero\n"
+        );
+
+        let output = Output::default();
+        trace(source, Arc::new(Mutex::new(JsonLines::new(output.clone()))));
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            r#"{"pc":0,"instruction":"LOAD $1 #7","changes":[{"register":1,"old":0,"new":7}],"location":null,"fault":null}
+{"pc":4,"instruction":"DIV $3 $1 $2","changes":[],"location":null,"fault":"division by zero"}
+"#
+        );
+    }
+}
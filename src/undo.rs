@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+/// What one instruction overwrote, so it can be undone
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct UndoEntry {
+    /// Address of the instruction, where the pc goes back to
+    pub pc: usize,
+    pub remainder: u32,
+    pub halted: bool,
+    /// Registers the instruction wrote, with the value they had before
+    pub registers: Vec<(usize, i32)>,
+    /// Size of the heap before an `ALOC`
+    pub heap_size: Option<usize>,
+}
+
+/// The last `capacity` instructions a VM executed, newest last
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct UndoLog {
+    capacity: usize,
+    entries: VecDeque<UndoEntry>,
+}
+
+impl UndoLog {
+    pub fn new(capacity: usize) -> UndoLog {
+        UndoLog {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Drops the oldest entries that don't fit in the new capacity
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Starts the entry of the instruction that is about to execute
+    pub fn push(&mut self, entry: UndoEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        self.entries.pop_back()
+    }
+
+    /// The entry of the instruction that is executing
+    pub fn current(&mut self) -> Option<&mut UndoEntry> {
+        self.entries.back_mut()
+    }
+
+    /// The entry of the instruction that executed last
+    pub fn last(&self) -> Option<&UndoEntry> {
+        self.entries.back()
+    }
+
+    /// How many instructions back the last write to `register` is, 1 being
+    /// the last instruction executed
+    pub fn last_write(&self, register: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .rev()
+            .position(|entry| entry.registers.iter().any(|(r, _)| *r == register))
+            .map(|position| position + 1)
+    }
+}
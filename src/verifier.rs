@@ -0,0 +1,207 @@
+use std::fmt::Display;
+
+use crate::{
+    instruction::{loaded_jump, DecodeError, Instruction, Opcode, INSTRUCTION_SIZE},
+    vm::REGISTER_COUNT,
+};
+
+/// The first problem `verify` found, at the instruction at `pc`
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyError {
+    pub pc: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VerifyErrorKind {
+    /// The program isn't a whole number of instructions long
+    Misaligned {
+        length: usize,
+    },
+    IllegalOpcode {
+        opcode: u8,
+    },
+    InvalidRegister {
+        register: u8,
+    },
+    /// A jump to a loaded address that isn't the start of an instruction
+    InvalidJumpTarget {
+        target: i64,
+    },
+    /// The last instruction is neither `HLT` nor an unconditional jump, so
+    /// execution could run past the end of the program
+    MissingTerminator,
+}
+
+/// Checks the program before it runs: that it is made of whole instructions
+/// with known opcodes and registers that exist, that it ends in `HLT` or an
+/// unconditional jump, and that jumps to addresses loaded with `LOAD` land on
+/// an instruction. Jumps to computed addresses can't be checked here, the VM
+/// still faults on those.
+pub fn verify(program: &[u8]) -> Result<(), VerifyError> {
+    let length = program.len();
+    if !length.is_multiple_of(INSTRUCTION_SIZE) {
+        return Err(VerifyError {
+            pc: length - length % INSTRUCTION_SIZE,
+            kind: VerifyErrorKind::Misaligned { length },
+        });
+    }
+
+    let mut instructions = vec![];
+    for (index, bytes) in program.chunks_exact(INSTRUCTION_SIZE).enumerate() {
+        let pc = index * INSTRUCTION_SIZE;
+        let instruction = Instruction::decode_checked(bytes.try_into().expect("chunks are exact"))
+            .map_err(|error| VerifyError {
+                pc,
+                kind: error.into(),
+            })?;
+
+        instructions.push(Some(instruction));
+    }
+
+    for index in 0..instructions.len() {
+        if let Some((jump, target)) = loaded_jump(&instructions, index) {
+            let on_instruction = target >= 0
+                && (target as usize) < length
+                && (target as usize).is_multiple_of(INSTRUCTION_SIZE);
+            if !on_instruction {
+                return Err(VerifyError {
+                    pc: jump * INSTRUCTION_SIZE,
+                    kind: VerifyErrorKind::InvalidJumpTarget { target },
+                });
+            }
+        }
+    }
+
+    match instructions
+        .last()
+        .copied()
+        .flatten()
+        .map(|last| last.opcode())
+    {
+        Some(Opcode::HLT | Opcode::JMP | Opcode::JMPB | Opcode::JMPF) => Ok(()),
+        _ => Err(VerifyError {
+            pc: length.saturating_sub(INSTRUCTION_SIZE),
+            kind: VerifyErrorKind::MissingTerminator,
+        }),
+    }
+}
+
+impl From<DecodeError> for VerifyErrorKind {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::IllegalOpcode { opcode } => VerifyErrorKind::IllegalOpcode { opcode },
+            DecodeError::InvalidRegister { register } => {
+                VerifyErrorKind::InvalidRegister { register }
+            }
+        }
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pc {}: {}", self.pc, self.kind)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyErrorKind::Misaligned { length } => write!(
+                f,
+                "the program is {length} bytes long, which isn't a whole number of instructions"
+            ),
+            VerifyErrorKind::IllegalOpcode { opcode } => write!(f, "illegal opcode {opcode}"),
+            VerifyErrorKind::InvalidRegister { register } => write!(
+                f,
+                "register ${register} does not exist, registers go from $0 to ${}",
+                REGISTER_COUNT - 1
+            ),
+            VerifyErrorKind::InvalidJumpTarget { target } => {
+                write!(
+                    f,
+                    "jump to {target}, which isn't the start of an instruction"
+                )
+            }
+            VerifyErrorKind::MissingTerminator => {
+                write!(f, "the program doesn't end in HLT or a jump")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assembler::Assembler;
+
+    fn error(program: &[u8]) -> (usize, VerifyErrorKind) {
+        let error = verify(program).unwrap_err();
+        (error.pc, error.kind)
+    }
+
+    #[test]
+    fn test_verify() {
+        let program = Assembler::new()
+            .assemble(
+                "LOAD $1 #3\n\
+                 loop: DEC $1\n\
+                 GT $2 $1 $0\n\
+                 LOAD $3 @loop\n\
+                 JEQ $3 $2\n\
+                 BLT $1 $2 @loop\n\
+                 HLT",
+            )
+            .unwrap();
+        assert_eq!(verify(&program), Ok(()));
+
+        // A loop that jumps back forever ends in a jump
+        assert_eq!(verify(&[18, 1, 0, 0, 0, 2, 0, 0, 6, 2, 0, 0]), Ok(()));
+        // JMPF $1 with $1 = 6 goes from 4 + 2 to the HLT at 12
+        assert_eq!(
+            verify(&[0, 1, 0, 6, 8, 1, 0, 0, 18, 1, 0, 0, 5, 0, 0, 0]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        assert_eq!(
+            error(&[5, 0, 0, 0, 5, 0]),
+            (4, VerifyErrorKind::Misaligned { length: 6 })
+        );
+        assert_eq!(
+            error(&[18, 1, 0, 0, 99, 0, 0, 0]),
+            (4, VerifyErrorKind::IllegalOpcode { opcode: 99 })
+        );
+        assert_eq!(
+            error(&[1, 1, 2, 32, 5, 0, 0, 0]),
+            (0, VerifyErrorKind::InvalidRegister { register: 32 })
+        );
+        assert_eq!(
+            error(&[18, 1, 0, 0]),
+            (0, VerifyErrorKind::MissingTerminator)
+        );
+        assert_eq!(error(&[]), (0, VerifyErrorKind::MissingTerminator));
+
+        // LOAD $2 #6, INC $1, JMP $2
+        assert_eq!(
+            error(&[0, 2, 0, 6, 18, 1, 0, 0, 6, 2, 0, 0]),
+            (8, VerifyErrorKind::InvalidJumpTarget { target: 6 })
+        );
+        // LOAD $2 #12, JEQ $2 $1, HLT: one past the end
+        assert_eq!(
+            error(&[0, 2, 0, 12, 15, 2, 1, 0, 5, 0, 0, 0]),
+            (4, VerifyErrorKind::InvalidJumpTarget { target: 12 })
+        );
+        // LOAD $2 #4, JMPB $2 goes back from 6 to 2
+        assert_eq!(
+            error(&[0, 2, 0, 4, 7, 2, 0, 0]),
+            (4, VerifyErrorKind::InvalidJumpTarget { target: 2 })
+        );
+        // The register is written before the jump, its value isn't known
+        assert_eq!(verify(&[0, 2, 0, 6, 18, 2, 0, 0, 6, 2, 0, 0]), Ok(()));
+    }
+}
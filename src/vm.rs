@@ -1,220 +1,739 @@
 #![allow(dead_code)]
 
-use crate::instruction::Opcode;
-
-pub struct VM {
-    pub registers: [i32; 32],
+use std::fmt::Display;
+
+use crate::{
+    debug_info::{DebugInfo, SourceLocation},
+    disassembler::decode,
+    hooks::{NoHooks, VmHooks},
+    instruction::{predecode, DecodeError, Instruction, Opcode, INSTRUCTION_SIZE},
+    snapshot::Snapshot,
+    trace::{Change, TraceEvent, Tracer},
+    undo::{UndoEntry, UndoLog},
+};
+
+/// Number of registers, they are numbered from 0
+pub const REGISTER_COUNT: usize = 32;
+
+#[derive(Clone)]
+pub struct VM<H: VmHooks = NoHooks> {
+    pub registers: [i32; REGISTER_COUNT],
     pc: usize,
     pub program: Vec<u8>,
     remainder: u32,
     heap: Vec<u8>,
+    /// Used to name the source line of an instruction that faults
+    pub debug_info: Option<DebugInfo>,
+    fault: Option<Fault>,
+    /// Whether the last instruction was `HLT`
+    halted: bool,
+    /// Receives every executed instruction while set
+    tracer: Option<Tracer>,
+    hooks: H,
+    /// What each instruction costs from the budget of `run_with_budget`
+    pub costs: Costs,
+    pub limits: VmLimits,
+    /// Number of `ALOC`s executed since the VM was created or reset
+    allocations: u64,
+    /// What the last instructions overwrote, while history is kept
+    history: Option<UndoLog>,
+}
+
+/// How a run that didn't fault ended
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RunStatus {
+    Halted,
+    /// Execution moved past the end of the program
+    End,
+    /// The next instruction costs more than the `remaining` budget. The VM
+    /// stops in front of it, running it again with more fuel continues.
+    OutOfFuel {
+        remaining: u64,
+    },
+}
+
+/// What executing an instruction costs, by opcode. Every opcode costs 1
+/// unless it is set otherwise, so a budget counts instructions.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Costs([u64; Opcode::ALL.len()]);
+
+impl Costs {
+    pub fn get(&self, opcode: Opcode) -> u64 {
+        match opcode {
+            // Illegal opcodes fault without executing
+            Opcode::IGL => 0,
+            opcode => self.0[u8::from(opcode) as usize],
+        }
+    }
+
+    /// Sets what `opcode` costs. A cost of 0 makes it free, so a loop of
+    /// free instructions never runs out of fuel.
+    pub fn set(&mut self, opcode: Opcode, cost: u64) {
+        if opcode != Opcode::IGL {
+            self.0[u8::from(opcode) as usize] = cost;
+        }
+    }
+}
+
+impl Default for Costs {
+    fn default() -> Self {
+        Costs([1; Opcode::ALL.len()])
+    }
+}
+
+/// Caps on the resources a program can use, so a program that runs in a
+/// sandbox can't exhaust the memory of the host. Nothing is capped by default.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VmLimits {
+    pub max_heap_bytes: usize,
+    /// How many times `ALOC` can be executed
+    pub max_allocations: u64,
+    pub max_program_bytes: usize,
+}
+
+impl Default for VmLimits {
+    fn default() -> Self {
+        VmLimits {
+            max_heap_bytes: usize::MAX,
+            max_allocations: u64::MAX,
+            max_program_bytes: usize::MAX,
+        }
+    }
+}
+
+/// A limit of `VmLimits` with the value it was set to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limit {
+    HeapBytes { max: usize },
+    Allocations { max: u64 },
+    ProgramBytes { max: usize },
+}
+
+/// An instruction that could not be executed, the VM stops in front of it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// Address of the instruction that faulted
+    pub pc: usize,
+    /// Source of the instruction, when the VM has debug info for it
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FaultKind {
+    IllegalOpcode {
+        opcode: u8,
+    },
+    InvalidRegister {
+        register: u8,
+    },
+    /// The program ends before the instruction does
+    TruncatedInstruction,
+    DivisionByZero,
+    /// A relative jump that would move before the start of the program
+    InvalidJump {
+        offset: i32,
+    },
+    /// `ALOC` with a negative number of bytes
+    InvalidAllocation {
+        bytes: i32,
+    },
+    /// Executing the instruction would go past one of the `VmLimits`
+    LimitExceeded(Limit),
+}
+
+impl From<DecodeError> for FaultKind {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::IllegalOpcode { opcode } => FaultKind::IllegalOpcode { opcode },
+            DecodeError::InvalidRegister { register } => FaultKind::InvalidRegister { register },
+        }
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
+        VM::with_hooks(NoHooks)
+    }
+}
+
+impl<H: VmHooks> VM<H> {
+    /// A VM that calls `hooks` while it executes
+    pub fn with_hooks(hooks: H) -> VM<H> {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
             pc: 0,
             program: vec![],
             remainder: 0,
             heap: vec![],
+            debug_info: None,
+            fault: None,
+            halted: false,
+            tracer: None,
+            hooks,
+            costs: Costs::default(),
+            limits: VmLimits::default(),
+            allocations: 0,
+            history: None,
+        }
+    }
+
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut H {
+        &mut self.hooks
+    }
+
+    /// Puts the VM back in the state it started in, keeping the program
+    pub fn reset(&mut self) {
+        self.registers = [0; REGISTER_COUNT];
+        self.pc = 0;
+        self.remainder = 0;
+        self.heap.clear();
+        self.fault = None;
+        self.halted = false;
+        self.allocations = 0;
+        self.clear_history();
+    }
+
+    /// Copies the state of the machine, see `Snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            pc: self.pc,
+            remainder: self.remainder,
+            program: self.program.clone(),
+            heap: self.heap.clone(),
+            halted: self.halted,
+            allocations: self.allocations,
+        }
+    }
+
+    /// Puts the machine in the state of the snapshot. A fault of the last
+    /// instruction is cleared, running again repeats it.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.remainder = snapshot.remainder;
+        self.program = snapshot.program;
+        self.heap = snapshot.heap;
+        self.halted = snapshot.halted;
+        self.allocations = snapshot.allocations;
+        self.fault = None;
+        self.clear_history();
+    }
+
+    /// Keeps what the last `size` instructions overwrote, so they can be
+    /// undone with `step_back`. A size of 0 stops keeping history.
+    pub fn set_history_size(&mut self, size: usize) {
+        match (&mut self.history, size) {
+            (_, 0) => self.history = None,
+            (Some(history), size) => history.set_capacity(size),
+            (None, size) => self.history = Some(UndoLog::new(size)),
+        }
+    }
+
+    /// Number of instructions that can be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, UndoLog::len)
+    }
+
+    /// Number of instructions the history keeps, 0 when it is off
+    pub fn history_size(&self) -> usize {
+        self.history.as_ref().map_or(0, UndoLog::capacity)
+    }
+
+    /// Registers the last executed instruction wrote, as recorded by the
+    /// history. `None` when no history is kept.
+    pub(crate) fn last_written_registers(&self) -> Option<Vec<usize>> {
+        let history = self.history.as_ref()?;
+        let entry = history.last();
+
+        Some(entry.map_or(vec![], |entry| {
+            entry
+                .registers
+                .iter()
+                .map(|(register, _)| *register)
+                .collect()
+        }))
+    }
+
+    fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            *history = UndoLog::new(history.capacity());
+        }
+    }
+
+    /// Undoes the last `count` instructions, or as many as the history
+    /// goes back. Returns how many were undone.
+    pub fn step_back(&mut self, count: usize) -> usize {
+        for undone in 0..count {
+            let entry = match self.history.as_mut().and_then(UndoLog::pop) {
+                Some(entry) => entry,
+                None => return undone,
+            };
+
+            for (register, value) in entry.registers.into_iter().rev() {
+                self.registers[register] = value;
+            }
+            if let Some(size) = entry.heap_size {
+                self.heap.truncate(size);
+                self.allocations -= 1;
+            }
+            self.pc = entry.pc;
+            self.remainder = entry.remainder;
+            self.halted = entry.halted;
+            self.fault = None;
+        }
+
+        count
+    }
+
+    /// Goes back to the last instruction that wrote `register`, so it is the
+    /// next to execute. Returns false and changes nothing when the history
+    /// doesn't go back that far.
+    pub fn reverse_to_write(&mut self, register: usize) -> bool {
+        match self
+            .history
+            .as_ref()
+            .and_then(|history| history.last_write(register))
+        {
+            Some(count) => {
+                self.step_back(count);
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn run(&mut self) {
+    /// Runs until the program halts, ends or faults
+    pub fn run(&mut self) -> Result<(), Fault> {
         let mut is_done = false;
         while !is_done {
             is_done = self.execute_instruction();
         }
+
+        match self.fault.take() {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs until the program halts, ends or faults, or until the next
+    /// instruction costs more than what is left of `budget`. Untrusted
+    /// programs can't run forever this way, and several VMs can take turns
+    /// by giving each of them a budget at a time.
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<RunStatus, Fault> {
+        let mut remaining = budget;
+
+        loop {
+            if let Some(opcode) = self.program.get(self.pc) {
+                let cost = self.costs.get(Opcode::from(*opcode));
+                if cost > remaining {
+                    return Ok(RunStatus::OutOfFuel { remaining });
+                }
+                remaining -= cost;
+            }
+
+            if self.execute_instruction() {
+                break;
+            }
+        }
+
+        match self.fault.take() {
+            Some(fault) => Err(fault),
+            None if self.halted => Ok(RunStatus::Halted),
+            None => Ok(RunStatus::End),
+        }
+    }
+
+    /// Like `run`, but decodes the program once up front and then executes
+    /// the decoded instructions, which skips the decoding and checking the
+    /// byte interpreter does for every instruction. Tracing and history are
+    /// recorded by the byte interpreter, with either of them on this is the
+    /// same as `run`.
+    pub fn run_fast(&mut self) -> Result<(), Fault> {
+        if self.tracer.is_some()
+            || self.history.is_some()
+            || self.program.len() > self.limits.max_program_bytes
+        {
+            return self.run();
+        }
+
+        let instructions = predecode(&self.program);
+        self.fault = None;
+        self.halted = false;
+
+        loop {
+            let start = self.pc;
+            let decoded = match instructions.get(start / INSTRUCTION_SIZE) {
+                Some(Some(instruction)) if start.is_multiple_of(INSTRUCTION_SIZE) => {
+                    Some(*instruction)
+                }
+                _ => None,
+            };
+
+            let is_done = match decoded {
+                Some(instruction) => {
+                    self.hooks.before_instruction(start, instruction.opcode());
+                    self.apply(start, instruction)
+                }
+                // The byte interpreter ends the program or raises the fault
+                None => self.execute(),
+            };
+            if is_done {
+                break;
+            }
+        }
+
+        match self.fault.take() {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
     }
 
     pub fn run_once(&mut self) {
         self.execute_instruction();
     }
 
+    /// The fault that stopped the last instruction, if it faulted
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    /// Whether the last instruction executed was `HLT`
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// Address of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Continues execution at `pc`
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Starts sending every executed instruction to the sink, or stops
+    /// tracing with `None`
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
     pub fn execute_instruction(&mut self) -> bool {
+        match self.tracer.clone() {
+            None => self.execute(),
+            Some(tracer) => self.execute_traced(&tracer),
+        }
+    }
+
+    /// Executes the instruction and reports what it changed to the tracer
+    fn execute_traced(&mut self, tracer: &Tracer) -> bool {
+        let pc = self.pc;
+        if pc >= self.program.len() {
+            return self.execute();
+        }
+
+        let registers = self.registers;
+        let remainder = self.remainder;
+        let heap_size = self.heap.len();
+        let end = self.program.len().min(pc + INSTRUCTION_SIZE);
+        let instruction = decode(&self.program[pc..end]).swap_remove(0).1;
+
+        let is_done = self.execute();
+
+        let mut changes: Vec<Change> = registers
+            .iter()
+            .zip(self.registers)
+            .enumerate()
+            .filter(|(_, (old, new))| *old != new)
+            .map(|(register, (old, new))| Change::Register {
+                register,
+                old: *old,
+                new,
+            })
+            .collect();
+        if remainder != self.remainder {
+            changes.push(Change::Remainder {
+                old: remainder,
+                new: self.remainder,
+            });
+        }
+        if heap_size != self.heap.len() {
+            changes.push(Change::Heap {
+                old_size: heap_size,
+                new_size: self.heap.len(),
+            });
+        }
+
+        let event = TraceEvent {
+            pc,
+            instruction,
+            changes,
+            location: self
+                .debug_info
+                .as_ref()
+                .and_then(|info| info.location(pc))
+                .cloned(),
+            fault: self.fault.as_ref().map(|fault| fault.kind.clone()),
+        };
+        // A sink that panicked while recording can still take events
+        let mut sink = tracer.lock().unwrap_or_else(|e| e.into_inner());
+        sink.record(&event);
+
+        is_done
+    }
+
+    fn execute(&mut self) -> bool {
+        let halted = self.halted;
+        self.fault = None;
+        self.halted = false;
         if self.pc >= self.program.len() {
             return true;
         }
 
-        match self.decode_opcode() {
-            Opcode::LOAD => {
-                let register = self.next_8_bites_usize();
-                let number = self.next_16_bits_usize();
-
-                self.registers[register] = number as i32;
-            }
-            Opcode::ADD => {
-                let load_register = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
+        let start = self.pc;
+        if let Some(history) = &mut self.history {
+            history.push(UndoEntry {
+                pc: start,
+                remainder: self.remainder,
+                halted,
+                ..Default::default()
+            });
+        }
+        let opcode = Opcode::from(self.program[start]);
+        self.hooks.before_instruction(start, opcode);
 
-                self.registers[load_register] = register_one + register_two;
-            }
-            Opcode::MUL => {
-                let load_register = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
+        if self.program.len() > self.limits.max_program_bytes {
+            let max = self.limits.max_program_bytes;
+            self.raise(FaultKind::LimitExceeded(Limit::ProgramBytes { max }), start);
+            return true;
+        }
 
-                self.registers[load_register] = register_one * register_two;
+        match self.fetch(start) {
+            Ok(instruction) => self.apply(start, instruction),
+            Err(kind) => {
+                self.raise(kind, start);
+                true
             }
-            Opcode::SUB => {
-                let load_register = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
+        }
+    }
 
-                self.registers[load_register] = register_one - register_two;
-            }
+    /// Executes an instruction that was read from `start` and checked,
+    /// returns whether execution stops after it
+    #[inline(always)]
+    fn apply(&mut self, start: usize, instruction: Instruction) -> bool {
+        let opcode = instruction.opcode();
+        let (a, b, c) = (instruction.a(), instruction.b(), instruction.c());
+        // Where the next instruction starts, unless this one jumps
+        self.pc = start + INSTRUCTION_SIZE;
+        // Relative jumps count from the byte after their register
+        let relative_to = start + 2;
+
+        match opcode {
+            Opcode::LOAD => self.set_register(a, instruction.immediate() as i32),
+            Opcode::ADD => self.set_register(a, self.registers[b].wrapping_add(self.registers[c])),
+            Opcode::MUL => self.set_register(a, self.registers[b].wrapping_mul(self.registers[c])),
+            Opcode::SUB => self.set_register(a, self.registers[b].wrapping_sub(self.registers[c])),
             Opcode::DIV => {
-                let load_register = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
+                let register_one = self.registers[b];
+                let register_two = self.registers[c];
 
-                self.registers[load_register] = register_one / register_two;
-                self.remainder = (register_one % register_two) as u32;
-            }
-            Opcode::JMP => {
-                let target = self.registers[self.next_8_bites_usize()];
-                self.pc = target as usize;
-            }
-            Opcode::JMPB => {
-                let target = self.registers[self.next_8_bites_usize()];
-                self.pc -= target as usize
-            }
-            Opcode::JMPF => {
-                let target = self.registers[self.next_8_bites_usize()];
-                self.pc += target as usize
-            }
-            Opcode::EQ => {
-                let target = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
-
-                match register_one == register_two {
-                    true => self.registers[target] = 1,
-                    false => self.registers[target] = 0,
-                }
-            }
-            Opcode::NEQ => {
-                let target = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
-
-                match register_one == register_two {
-                    true => self.registers[target] = 0,
-                    false => self.registers[target] = 1,
-                }
-            }
-            Opcode::GT => {
-                let target = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
-
-                match register_one > register_two {
-                    true => self.registers[target] = 1,
-                    false => self.registers[target] = 0,
+                if register_two == 0 {
+                    self.raise(FaultKind::DivisionByZero, start);
+                    return true;
                 }
+
+                self.set_register(a, register_one.wrapping_div(register_two));
+                self.remainder = register_one.wrapping_rem(register_two) as u32;
             }
-            Opcode::LT => {
-                let target = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
-
-                match register_one < register_two {
-                    true => self.registers[target] = 1,
-                    false => self.registers[target] = 0,
+            Opcode::JMP => self.pc = self.registers[a] as usize,
+            Opcode::JMPB => {
+                let offset = self.registers[a];
+                match relative_to.checked_sub(offset as usize) {
+                    Some(pc) => self.pc = pc,
+                    None => {
+                        self.raise(FaultKind::InvalidJump { offset }, start);
+                        return true;
+                    }
                 }
             }
-            Opcode::GTQ => {
-                let target = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
-
-                match register_one >= register_two {
-                    true => self.registers[target] = 1,
-                    false => self.registers[target] = 0,
+            Opcode::JMPF => self.pc = relative_to.saturating_add(self.registers[a] as usize),
+            Opcode::EQ => self.set_register(a, (self.registers[b] == self.registers[c]) as i32),
+            Opcode::NEQ => self.set_register(a, (self.registers[b] != self.registers[c]) as i32),
+            Opcode::GT => self.set_register(a, (self.registers[b] > self.registers[c]) as i32),
+            Opcode::LT => self.set_register(a, (self.registers[b] < self.registers[c]) as i32),
+            Opcode::GTQ => self.set_register(a, (self.registers[b] >= self.registers[c]) as i32),
+            Opcode::LTQ => self.set_register(a, (self.registers[b] <= self.registers[c]) as i32),
+            Opcode::JEQ => match self.registers[b] == 1 {
+                true => self.pc = self.registers[a] as usize,
+                false => self.skip_padding(start + 3),
+            },
+            Opcode::JNEQ => match self.registers[b] == 0 {
+                true => self.pc = self.registers[a] as usize,
+                false => self.skip_padding(start + 3),
+            },
+            Opcode::ALOC => {
+                let bytes = self.registers[a];
+                let old_size = self.heap.len();
+
+                if bytes < 0 {
+                    self.raise(FaultKind::InvalidAllocation { bytes }, start);
+                    return true;
                 }
-            }
-            Opcode::LTQ => {
-                let target = self.next_8_bites_usize();
-                let register_one = self.registers[self.next_8_bites_usize()];
-                let register_two = self.registers[self.next_8_bites_usize()];
-
-                match register_one <= register_two {
-                    true => self.registers[target] = 1,
-                    false => self.registers[target] = 0,
+                if self.allocations >= self.limits.max_allocations {
+                    let max = self.limits.max_allocations;
+                    self.raise(FaultKind::LimitExceeded(Limit::Allocations { max }), start);
+                    return true;
                 }
-            }
-            Opcode::JEQ => {
-                let target = self.next_8_bites_usize();
-                let bool_register = self.next_8_bites_usize();
-
-                if self.registers[bool_register] == 1 {
-                    self.pc = self.registers[target] as usize;
+                let new_size = match old_size.checked_add(bytes as usize) {
+                    Some(size) if size <= self.limits.max_heap_bytes => size,
+                    _ => {
+                        let max = self.limits.max_heap_bytes;
+                        self.raise(FaultKind::LimitExceeded(Limit::HeapBytes { max }), start);
+                        return true;
+                    }
+                };
+
+                if let Some(entry) = self.history.as_mut().and_then(UndoLog::current) {
+                    entry.heap_size = Some(old_size);
                 }
-            }
-            Opcode::JNEQ => {
-                let target = self.next_8_bites_usize();
-                let bool_register = self.next_8_bites_usize();
-
-                if self.registers[bool_register] == 0 {
-                    self.pc = self.registers[target] as usize;
+                self.allocations += 1;
+                self.heap.resize(new_size, 0);
+                self.hooks.on_alloc(old_size, new_size);
+                for address in old_size..new_size {
+                    self.hooks.on_heap_write(address, 0, 0);
                 }
-            }
-            Opcode::ALOC => {
-                let register = self.next_8_bites_usize();
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+                self.skip_padding(start + 2);
             }
             Opcode::INC => {
-                let register = self.next_8_bites_usize();
-                self.registers[register] = self.registers[register] + 1;
+                self.set_register(a, self.registers[a].wrapping_add(1));
+                self.skip_padding(start + 2);
             }
             Opcode::DEC => {
-                let register = self.next_8_bites_usize();
-                self.registers[register] = self.registers[register] - 1;
+                self.set_register(a, self.registers[a].wrapping_sub(1));
+                self.skip_padding(start + 2);
             }
             Opcode::HLT => {
-                println!("HLT encountered");
-                true;
-            }
-            Opcode::IGL => {
-                println!("IGL encountered");
-                true;
+                self.pc = start + 1;
+                self.halted = true;
+                self.hooks.after_instruction(start, opcode);
+                return true;
             }
+            Opcode::IGL => unreachable!("checked instructions have a known opcode"),
         }
 
+        self.hooks.after_instruction(start, opcode);
         false
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
+    /// Moves past the unused bytes of an instruction that is shorter than
+    /// 4 bytes and whose operands end at `end`, so the next instruction is
+    /// decoded from its first byte
+    fn skip_padding(&mut self, end: usize) {
+        self.pc = end.next_multiple_of(INSTRUCTION_SIZE);
+    }
+
+    fn set_register(&mut self, register: usize, value: i32) {
+        let old = std::mem::replace(&mut self.registers[register], value);
+        if let Some(entry) = self.history.as_mut().and_then(UndoLog::current) {
+            entry.registers.push((register, old));
+        }
+        self.hooks.on_register_write(register, old, value);
+    }
 
-        opcode
+    /// Decodes the instruction at `start` and checks that all of it is in
+    /// the program, that its opcode is known and that it only names
+    /// registers that exist, so executing it can't read out of bounds
+    fn fetch(&self, start: usize) -> Result<Instruction, FaultKind> {
+        let bytes = self
+            .program
+            .get(start..start + INSTRUCTION_SIZE)
+            .ok_or(FaultKind::TruncatedInstruction)?;
+        let instruction =
+            Instruction::decode_checked(bytes.try_into().expect("checked the length"))?;
+
+        Ok(instruction)
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
+    /// Stops the VM in front of the instruction at `pc`
+    fn raise(&mut self, kind: FaultKind, pc: usize) {
+        let location = self
+            .debug_info
+            .as_ref()
+            .and_then(|info| info.location(pc))
+            .cloned();
+
+        // The instruction didn't execute, so there is nothing to undo
+        if let Some(history) = &mut self.history {
+            history.pop();
+        }
 
-        result
+        let fault = Fault { kind, pc, location };
+        self.hooks.on_fault(&fault);
+        self.pc = pc;
+        self.fault = Some(fault);
     }
+}
 
-    fn next_8_bites_usize(&mut self) -> usize {
-        self.next_8_bits() as usize
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "fault at {}: {}", location, self.kind),
+            None => write!(f, "fault at pc {}: {}", self.pc, self.kind),
+        }
     }
+}
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
+impl std::error::Error for Fault {}
+
+impl Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultKind::IllegalOpcode { opcode } => write!(f, "illegal opcode {opcode}"),
+            FaultKind::InvalidRegister { register } => write!(
+                f,
+                "register ${register} does not exist, registers go from $0 to ${}",
+                REGISTER_COUNT - 1
+            ),
+            FaultKind::TruncatedInstruction => {
+                write!(f, "the program ends in the middle of an instruction")
+            }
+            FaultKind::DivisionByZero => write!(f, "division by zero"),
+            FaultKind::InvalidJump { offset } => {
+                write!(f, "jumping back {offset} bytes leaves the program")
+            }
+            FaultKind::InvalidAllocation { bytes } => {
+                write!(f, "can't allocate a negative number of bytes ({bytes})")
+            }
+            FaultKind::LimitExceeded(limit) => write!(f, "{limit}"),
+        }
+    }
+}
 
-        result
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::HeapBytes { max } => write!(f, "the heap can't grow past {max} bytes"),
+            Limit::Allocations { max } => write!(f, "no more than {max} allocations are allowed"),
+            Limit::ProgramBytes { max } => {
+                write!(f, "the program is larger than the limit of {max} bytes")
+            }
+        }
     }
+}
 
-    fn next_16_bits_usize(&mut self) -> usize {
-        self.next_16_bits() as usize
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -236,6 +755,7 @@ mod tests {
 
         test_vm.run_once();
         assert_eq!(test_vm.pc, 1);
+        assert!(test_vm.is_halted());
     }
 
     #[test]
@@ -245,7 +765,11 @@ mod tests {
         test_vm.program = test_bytes;
 
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(
+            test_vm.fault().map(|fault| &fault.kind),
+            Some(&FaultKind::IllegalOpcode { opcode: 254 })
+        );
     }
 
     #[test]
@@ -253,7 +777,7 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.program = vec![0, 0, 1, 244];
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 500)
     }
 
@@ -264,7 +788,7 @@ mod tests {
         test_vm.registers[2] = 500;
         test_vm.program = vec![1, 0, 1, 2];
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 1000)
     }
 
@@ -554,4 +1078,260 @@ mod tests {
 
         assert_eq!(test_vm.registers[0], 1);
     }
+
+    #[test]
+    fn test_short_instructions_skip_padding() {
+        let mut test_vm = VM::new();
+
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![18, 0, 0, 0, 16, 0, 1, 0, 19, 1, 0, 0, 18, 0, 0, 0];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps() {
+        let mut test_vm = VM::new();
+
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![1, 2, 0, 1, 3, 3, 0, 1];
+        test_vm.run().unwrap();
+
+        assert_eq!(test_vm.registers[2], i32::MIN + 1);
+        assert_eq!(test_vm.registers[3], -2);
+    }
+
+    #[test]
+    fn test_faults() {
+        let fault = |program: Vec<u8>| {
+            let mut test_vm = VM::new();
+            test_vm.program = program;
+            test_vm.run().unwrap_err()
+        };
+
+        assert_eq!(fault(vec![2, 0, 1, 2]).kind, FaultKind::DivisionByZero);
+        assert_eq!(
+            fault(vec![0, 0, 0, 1, 1, 40, 0, 0]),
+            Fault {
+                kind: FaultKind::InvalidRegister { register: 40 },
+                pc: 4,
+                location: None
+            }
+        );
+        assert_eq!(fault(vec![0, 0, 0]).kind, FaultKind::TruncatedInstruction);
+        assert_eq!(
+            fault(vec![0, 0, 0, 8, 7, 0, 0, 0]).kind,
+            FaultKind::InvalidJump { offset: 8 }
+        );
+    }
+
+    #[test]
+    fn test_step_back() {
+        use crate::assembler::assembler::Assembler;
+
+        let mut test_vm = VM::new();
+        test_vm.set_history_size(4);
+        test_vm.program = Assembler::new()
+            .assemble(
+                "LOAD $1 #7\n\
+                 LOAD $2 #2\n\
+                 DIV $3 $1 $2\n\
+                 ALOC $1\n\
+                 INC $2\n\
+                 HLT",
+            )
+            .unwrap();
+        let mut states = vec![test_vm.snapshot()];
+        while !test_vm.execute_instruction() {
+            states.push(test_vm.snapshot());
+        }
+        assert!(test_vm.is_halted());
+        assert_eq!(test_vm.history_len(), 4);
+
+        assert_eq!(test_vm.step_back(1), 1);
+        assert_eq!(test_vm.snapshot(), states[5]);
+        assert!(!test_vm.is_halted());
+
+        // Back to the DIV, the last write of $3
+        assert!(test_vm.reverse_to_write(3));
+        assert_eq!(test_vm.snapshot(), states[2]);
+        assert_eq!(test_vm.pc, 8);
+
+        // The history is bounded, the LOADs were forgotten
+        assert!(!test_vm.reverse_to_write(1));
+        assert_eq!(test_vm.step_back(5), 0);
+        assert_eq!(test_vm.snapshot(), states[2]);
+
+        // Replaying gives the same result
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 3);
+        assert_eq!(test_vm.remainder, 1);
+        assert_eq!(test_vm.heap.len(), 7);
+
+        // Faulting instructions aren't recorded
+        test_vm.program = vec![2, 0, 1, 4];
+        test_vm.reset();
+        assert!(test_vm.run().is_err());
+        assert_eq!(test_vm.history_len(), 0);
+    }
+
+    #[test]
+    fn test_run_fast() {
+        use crate::assembler::assembler::Assembler;
+
+        let programs = [
+            Assembler::new()
+                .assemble(
+                    "LOAD $1 #1000\n\
+                     loop: DEC $1\n\
+                     ADD $2 $2 $1\n\
+                     DIV $3 $2 $4\n\
+                     GT $5 $1 $0\n\
+                     LOAD $6 @loop\n\
+                     JEQ $6 $5\n\
+                     ALOC $4\n\
+                     HLT",
+                )
+                .unwrap(),
+            // Division by zero
+            vec![2, 0, 1, 2],
+            // An illegal opcode, then an invalid register
+            vec![18, 1, 0, 0, 99, 0, 0, 0],
+            vec![18, 1, 0, 0, 18, 40, 0, 0],
+            // A jump into the middle of an instruction, which decodes a LOAD
+            // there and then runs into the end of the program
+            vec![0, 1, 0, 2, 6, 1, 0, 0],
+        ];
+
+        for program in programs {
+            let mut bytes = VM::new();
+            let mut fast = VM::new();
+            bytes.registers[4] = 3;
+            fast.registers[4] = 3;
+            bytes.program = program.clone();
+            fast.program = program;
+
+            assert_eq!(fast.run_fast(), bytes.run());
+            assert_eq!(fast.snapshot(), bytes.snapshot());
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut test_vm = VM::new();
+        test_vm.limits = VmLimits {
+            max_heap_bytes: 10,
+            max_allocations: 2,
+            max_program_bytes: 12,
+        };
+        // ALOC $0 three times
+        test_vm.program = vec![17, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
+
+        test_vm.registers[0] = 8;
+        test_vm.run_once();
+        assert_eq!(test_vm.heap.len(), 8);
+
+        let fault = test_vm.run().unwrap_err();
+        assert_eq!(
+            fault.kind,
+            FaultKind::LimitExceeded(Limit::HeapBytes { max: 10 })
+        );
+        assert_eq!(
+            fault.to_string(),
+            "fault at pc 4: the heap can't grow past 10 bytes"
+        );
+        assert_eq!(test_vm.heap.len(), 8);
+
+        test_vm.registers[0] = 2;
+        assert_eq!(
+            test_vm.run().unwrap_err().kind,
+            FaultKind::LimitExceeded(Limit::Allocations { max: 2 })
+        );
+        assert_eq!(test_vm.heap.len(), 10);
+
+        test_vm.reset();
+        test_vm.registers[0] = -1;
+        assert_eq!(
+            test_vm.run().unwrap_err().kind,
+            FaultKind::InvalidAllocation { bytes: -1 }
+        );
+        assert!(test_vm.heap.is_empty());
+
+        test_vm.program.extend([5, 0, 0, 0]);
+        assert_eq!(
+            test_vm.run().unwrap_err().kind,
+            FaultKind::LimitExceeded(Limit::ProgramBytes { max: 12 })
+        );
+    }
+
+    #[test]
+    fn test_run_with_budget() {
+        let mut test_vm = VM::new();
+        // INC $1, JMP $2 loops forever
+        test_vm.program = vec![18, 1, 0, 0, 6, 2, 0, 0];
+
+        assert_eq!(
+            test_vm.run_with_budget(5),
+            Ok(RunStatus::OutOfFuel { remaining: 0 })
+        );
+        assert_eq!(test_vm.registers[1], 3);
+        assert_eq!(test_vm.pc, 4);
+
+        // Running again continues in front of the instruction it stopped at
+        test_vm.costs.set(Opcode::JMP, 10);
+        assert_eq!(
+            test_vm.run_with_budget(12),
+            Ok(RunStatus::OutOfFuel { remaining: 1 })
+        );
+        assert_eq!(test_vm.registers[1], 4);
+        assert_eq!(test_vm.pc, 4);
+
+        test_vm.program = vec![18, 1, 0, 0, 5, 0, 0, 0];
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run_with_budget(2), Ok(RunStatus::Halted));
+        test_vm.pc = 0;
+        test_vm.program.truncate(4);
+        assert_eq!(test_vm.run_with_budget(1), Ok(RunStatus::End));
+        test_vm.pc = 0;
+        test_vm.program = vec![2, 0, 1, 0];
+        assert_eq!(
+            test_vm.run_with_budget(1).unwrap_err().kind,
+            FaultKind::DivisionByZero
+        );
+    }
+
+    #[test]
+    fn test_fault_location() {
+        use crate::assembler::assembler::Assembler;
+
+        let mut assembler = Assembler::new();
+        assembler.generate_debug_info = true;
+        let program = assembler
+            .assemble(
+                "LOAD $1 #4
+CLR $2
+
+DIV $3 $1 $2
+HLT",
+            )
+            .unwrap();
+
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        let fault = test_vm.run().unwrap_err();
+        assert_eq!(fault.to_string(), "fault at pc 8: division by zero");
+
+        let mut info = assembler.debug_info.unwrap();
+        for range in &mut info.ranges {
+            range.location.file = Some(String::from("main.sasm"));
+        }
+        test_vm.debug_info = Some(info);
+
+        let fault = test_vm.run().unwrap_err();
+        assert_eq!(fault.pc, 8);
+        assert_eq!(fault.to_string(), "fault at main.sasm:4: division by zero");
+    }
 }